@@ -0,0 +1,49 @@
+//! 合成したグラデーション画像の中央にモザイクをかけ、一時ディレクトリにPNGで書き出す。
+//!
+//! `cargo run --example mosaic_region`
+
+use anyhow::Result;
+use my_img_utilities::filter::{
+    mosaic::{MosaicFilter, MosaicFilterOption},
+    AppFilter,
+};
+use my_img_utilities::{
+    encode_image, read_image, write_binary, BlendMode, BlendOptions, BorderPolicy, EncodeOptions,
+    FilterProcess, OutputFormat, Pipeline, PixelBuffer, Region,
+};
+
+fn main() -> Result<()> {
+    let img = PixelBuffer::U8(image::RgbaImage::from_fn(256, 256, |x, y| {
+        image::Rgba([x as u8, y as u8, 255 - x as u8, 255])
+    }));
+
+    let mosaic = AppFilter::new(MosaicFilter::new(MosaicFilterOption::new(16)));
+    let region = Region::Ellipse {
+        x: 64,
+        y: 64,
+        width: 128,
+        height: 128,
+    };
+    let pipeline = Pipeline::new(BorderPolicy::Clamp).push(FilterProcess::new(
+        mosaic,
+        region,
+        BlendOptions::new(8, 1.0, BlendMode::Normal),
+    ));
+    let out = pipeline.run(img)?;
+
+    let path = std::env::temp_dir().join("mosaic_region.png");
+    let buf = encode_image(
+        out,
+        OutputFormat::Png,
+        &EncodeOptions::default(),
+        None,
+        &Default::default(),
+    )?;
+    write_binary(&path, &buf)?;
+
+    // 書き出したファイルを読み戻して確かめる
+    let written = read_image(&path)?;
+    assert_eq!(written.buffer.dimensions(), (256, 256));
+    println!("{}", path.display());
+    Ok(())
+}
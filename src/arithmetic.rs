@@ -1,6 +1,61 @@
-use num_traits::{Num, ToPrimitive, Zero};
+use image::{ImageBuffer, Pixel, Primitive, Rgba};
+use num_traits::{Num, NumCast, ToPrimitive, Zero};
 use std::ops::{Add, Deref, Div, Mul, Sub};
 
+use crate::process::{DynFilterProcessor, SourceView};
+
+/// パイプラインで扱うサブピクセルの型 (u8, u16, f32)。
+pub trait Subpixel: Primitive + std::fmt::Debug + Send + Sync + 'static {
+    /// 色として最大の値をf64で返す。浮動小数点数では1.0になる。
+    #[inline]
+    fn max_f64() -> f64 {
+        Self::DEFAULT_MAX_VALUE.to_f64().unwrap()
+    }
+    /// f64で計算した値を変換する。整数型では四捨五入して0から最大値の間に収める。
+    /// 浮動小数点数は範囲外の値も持てるのでそのまま変換する。
+    fn round_from_f64(value: f64) -> Self;
+    /// トレイトオブジェクトのフィルタから、この型に対応する処理を呼ぶ。
+    fn process_dyn(
+        filter: &dyn DynFilterProcessor,
+        src: &SourceView<Self>,
+    ) -> ImageBuffer<Rgba<Self>, Vec<Self>>
+    where
+        Rgba<Self>: Pixel<Subpixel = Self>;
+}
+impl Subpixel for u8 {
+    fn round_from_f64(value: f64) -> Self {
+        value.round().clamp(0.0, Self::max_f64()) as u8
+    }
+    fn process_dyn(
+        filter: &dyn DynFilterProcessor,
+        src: &SourceView<u8>,
+    ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        filter.process_u8(src)
+    }
+}
+impl Subpixel for u16 {
+    fn round_from_f64(value: f64) -> Self {
+        value.round().clamp(0.0, Self::max_f64()) as u16
+    }
+    fn process_dyn(
+        filter: &dyn DynFilterProcessor,
+        src: &SourceView<u16>,
+    ) -> ImageBuffer<Rgba<u16>, Vec<u16>> {
+        filter.process_u16(src)
+    }
+}
+impl Subpixel for f32 {
+    fn round_from_f64(value: f64) -> Self {
+        value as f32
+    }
+    fn process_dyn(
+        filter: &dyn DynFilterProcessor,
+        src: &SourceView<f32>,
+    ) -> ImageBuffer<Rgba<f32>, Vec<f32>> {
+        filter.process_f32(src)
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct TripleNums<T: Num + ToPrimitive + Copy>(pub [T; 3]);
 impl<T: Num + ToPrimitive + Copy> TripleNums<T> {
@@ -12,15 +67,23 @@ impl<T: Num + ToPrimitive + Copy> TripleNums<T> {
             self[2].to_f64().unwrap(),
         ])
     }
+    /// 別の数値型に変換する。整数型への変換では小数部を切り捨てる。
     #[inline]
-    pub fn to_u8(self) -> TripleNums<u8> {
+    pub fn cast<U: Num + NumCast + ToPrimitive + Copy>(self) -> TripleNums<U> {
         TripleNums([
-            self[0].to_u8().unwrap(),
-            self[1].to_u8().unwrap(),
-            self[2].to_u8().unwrap(),
+            U::from(self[0]).unwrap(),
+            U::from(self[1]).unwrap(),
+            U::from(self[2]).unwrap(),
         ])
     }
 }
+impl TripleNums<f64> {
+    /// サブピクセルの型に丸めて変換する。`Subpixel::round_from_f64`を参照。
+    #[inline]
+    pub fn round<S: Subpixel>(self) -> TripleNums<S> {
+        TripleNums(self.0.map(S::round_from_f64))
+    }
+}
 impl<T: Num + ToPrimitive + Copy> Zero for TripleNums<T> {
     fn zero() -> Self {
         TripleNums([T::zero(); 3])
@@ -0,0 +1,306 @@
+use anyhow::{anyhow, bail, Context, Result};
+use image::ImageFormat;
+use std::collections::HashSet;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::io::{is_heic_path, read_image, write_binary, ImageData};
+use crate::metadata::{exif_capture_time, MetadataPolicy};
+use crate::naming::{NameFields, OutputNaming, OutputTarget};
+use crate::output::{encode_image, EncodeOptions, OutputFormat};
+use crate::pipeline::Pipeline;
+
+/// 展開した入力ファイル。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputFile {
+    pub path: PathBuf,
+    /// ディレクトリを指定したとき、そのディレクトリからファイルのあるディレクトリへの相対パス。
+    /// 出力ディレクトリの下に同じ構成を作るのに使う。
+    pub relative_dir: PathBuf,
+    /// このファイルを見つけた起点。ディレクトリならそのディレクトリ、globならパターンの文字を含まない先頭の部分、
+    /// ファイルを指定したときはそのファイル。
+    pub root: PathBuf,
+}
+
+/// パスにglobのパターン文字が含まれるか。
+pub fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// globパターンのうち、パターンの文字を含まない先頭のディレクトリ。
+fn glob_base(pattern: &Path) -> PathBuf {
+    let base = pattern
+        .components()
+        .take_while(|component| !is_glob(Path::new(component.as_os_str())))
+        .collect::<PathBuf>();
+    match base.as_os_str().is_empty() {
+        true => PathBuf::from("."),
+        false => base,
+    }
+}
+
+/// 読み込める画像の拡張子を持つか。
+pub fn is_image_path(path: &Path) -> bool {
+    is_heic_path(path) || ImageFormat::from_path(path).is_ok()
+}
+
+/// ファイル、ディレクトリ、globパターンを並べた入力を、処理するファイルの一覧に展開する。
+/// ディレクトリは画像の拡張子を持つファイルだけを拾い、`recursive`ならサブディレクトリもたどる。
+/// 同じファイルは1度だけ含める。何も見つからない指定はエラーにする。
+pub fn expand_inputs(inputs: &[PathBuf], recursive: bool) -> Result<Vec<InputFile>> {
+    let mut files = Vec::new();
+    let mut seen = HashSet::new();
+    for input in inputs {
+        let found = if is_glob(input) {
+            expand_glob(input)?
+        } else if input.is_dir() {
+            let mut found = Vec::new();
+            collect_dir(input, input, Path::new(""), recursive, &mut found)?;
+            found
+        } else {
+            fs::metadata(input).with_context(|| format!("cannot open {}", input.display()))?;
+            vec![InputFile {
+                path: input.clone(),
+                relative_dir: PathBuf::new(),
+                root: input.clone(),
+            }]
+        };
+        if found.is_empty() {
+            bail!("no image files found in {}", input.display());
+        }
+        for file in found {
+            let key = fs::canonicalize(&file.path).unwrap_or_else(|_| file.path.clone());
+            if seen.insert(key) {
+                files.push(file);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn expand_glob(pattern: &Path) -> Result<Vec<InputFile>> {
+    let root = glob_base(pattern);
+    let pattern = pattern.to_string_lossy();
+    let mut found = Vec::new();
+    for entry in glob::glob(&pattern).with_context(|| format!("invalid pattern: {}", pattern))? {
+        let path = entry?;
+        if path.is_file() {
+            found.push(InputFile {
+                path,
+                relative_dir: PathBuf::new(),
+                root: root.clone(),
+            });
+        }
+    }
+    Ok(found)
+}
+
+fn collect_dir(
+    root: &Path,
+    dir: &Path,
+    relative_dir: &Path,
+    recursive: bool,
+    found: &mut Vec<InputFile>,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("cannot read directory {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            if recursive {
+                let relative_dir = relative_dir.join(path.file_name().unwrap_or_default());
+                collect_dir(root, &path, &relative_dir, recursive, found)?;
+            }
+        } else if is_image_path(&path) {
+            found.push(InputFile {
+                path,
+                relative_dir: relative_dir.to_path_buf(),
+                root: root.to_path_buf(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// バッチで処理する入力と、入力の中での番号。
+#[derive(Debug, Clone)]
+pub struct BatchItem {
+    pub input: InputFile,
+    /// 1から数えた番号。
+    pub index: usize,
+    /// 入力の数。
+    pub total: usize,
+}
+impl BatchItem {
+    /// 1つだけのファイルを処理するときの入力。
+    pub fn single<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        Self {
+            input: InputFile {
+                root: path.clone(),
+                path,
+                relative_dir: PathBuf::new(),
+            },
+            index: 1,
+            total: 1,
+        }
+    }
+}
+
+/// 1つのファイルを処理した結果。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileOutcome {
+    /// 書き出したパス。
+    Written(PathBuf),
+    /// 出力先が重なったため書き出さなかった。理由を持つ。
+    Skipped(String),
+}
+
+/// 1つのファイルを読み込み、パイプラインを適用して指定のフォーマットで書き出す。
+/// 出力先は`naming`で決め、ディレクトリがなければ作る。テンプレートが画像の大きさや撮影日時を使うときは、
+/// 読み込んでから決める。それ以外は読み込む前に決め、書き出さないファイルはデコードしない。
+/// ICCプロファイルは引き継ぎ、メタデータは`metadata_policy`に従って残す。
+pub fn process_file(
+    item: &BatchItem,
+    naming: &OutputNaming,
+    pipeline: &Pipeline,
+    encode: &EncodeOptions,
+    metadata_policy: &MetadataPolicy,
+) -> Result<FileOutcome> {
+    let input = &item.input.path;
+    let mut fields = NameFields {
+        stem: input
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+        ext: encode
+            .format
+            .or_else(|| OutputFormat::from_path(input))
+            .map_or("jpg", |format| format.extension()),
+        dir: item.input.relative_dir.clone(),
+        index: item.index,
+        total: item.total,
+        filters: pipeline
+            .processes
+            .iter()
+            .map(|process| process.filter.name())
+            .collect(),
+        ..Default::default()
+    };
+    // 出力先に画像の中身が要らなければ、飛ばすファイルをデコードしないように先に決める
+    let claimed = match naming.needs_image() {
+        true => None,
+        false => match naming.claim(input, &fields)? {
+            OutputTarget::Write(output) => Some(output),
+            OutputTarget::Skip(reason) => return Ok(FileOutcome::Skipped(reason)),
+        },
+    };
+    let ImageData {
+        buffer,
+        icc,
+        metadata,
+    } = read_image(input)?;
+    let output = match claimed {
+        Some(output) => output,
+        None => {
+            (fields.width, fields.height) = buffer.dimensions();
+            fields.date = metadata.exif.as_deref().and_then(exif_capture_time);
+            match naming.claim(input, &fields)? {
+                OutputTarget::Write(output) => output,
+                OutputTarget::Skip(reason) => return Ok(FileOutcome::Skipped(reason)),
+            }
+        }
+    };
+    let format = encode.resolve_format(&output)?;
+    let metadata = metadata.filtered(metadata_policy)?;
+    let img = pipeline.run(buffer)?;
+    let buf = encode_image(img, format, encode, icc.as_ref(), &metadata)?;
+    if let Some(parent) = output
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    write_binary(&output, &buf)?;
+    Ok(FileOutcome::Written(output))
+}
+
+/// 1つのファイルの処理結果。
+#[derive(Debug)]
+pub struct BatchOutcome {
+    pub item: BatchItem,
+    pub result: Result<FileOutcome>,
+    /// 処理にかかった時間。
+    pub elapsed: Duration,
+}
+
+/// `jobs`個のファイルを同時に処理する。フィルタ自体もrayonで並列に動くので、
+/// 同時に処理するファイルの数はメモリの使用量で決める。
+/// ファイルの処理が終わるたびに、終わった数と結果を`progress`に渡す。結果は入力の順に返す。
+pub fn run_batch<F, G>(
+    items: &[BatchItem],
+    jobs: usize,
+    process: F,
+    progress: G,
+) -> Vec<BatchOutcome>
+where
+    F: Fn(&BatchItem) -> Result<FileOutcome> + Sync,
+    G: Fn(usize, &BatchOutcome) + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let done = Mutex::new(0usize);
+    let outcomes = Mutex::new(Vec::with_capacity(items.len()));
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+                let start = Instant::now();
+                // 1つのファイルでpanicしても、そのファイルの失敗として残りの処理を続ける
+                let result = panic::catch_unwind(AssertUnwindSafe(|| process(item)))
+                    .unwrap_or_else(|payload| {
+                        Err(anyhow!("panicked: {}", panic_message(&payload)))
+                    });
+                let outcome = BatchOutcome {
+                    item: item.clone(),
+                    result,
+                    elapsed: start.elapsed(),
+                };
+                // 進捗の表示が前後しないよう、数え上げと通知をまとめてロックする
+                {
+                    let mut done = done.lock().unwrap_or_else(PoisonError::into_inner);
+                    *done += 1;
+                    progress(*done, &outcome);
+                }
+                outcomes
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push((index, outcome));
+            });
+        }
+    });
+    let mut outcomes = outcomes
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner);
+    outcomes.sort_by_key(|(index, _)| *index);
+    outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+}
+
+/// panicの値からメッセージを取り出す。
+fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown error")
+}
@@ -0,0 +1,174 @@
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use super::interactive::input::AppParams;
+use my_img_utilities::batch::{
+    expand_inputs, process_file, run_batch, BatchItem, FileOutcome, InputFile,
+};
+use my_img_utilities::error::AppError;
+use my_img_utilities::naming::OutputNaming;
+use my_img_utilities::pipeline::Pipeline;
+
+/// 同時に処理するファイルの数のデフォルト。CPUの数で、多くても4にする。
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get().min(4))
+}
+
+/// 入力を展開して、同じフィルタを各ファイルに並列に適用する。出力先は`naming`で決める。
+/// 進捗を1ファイルごとに表示し、最後に集計と失敗したファイルの一覧を表示する。
+/// 1つでも失敗すれば`AppError::Batch`を返す。
+pub fn process_batch(
+    app_params: &AppParams,
+    pipeline: &Pipeline,
+    naming: &OutputNaming,
+    jobs: usize,
+) -> Result<()> {
+    // 出力ディレクトリが入力のディレクトリやglobの中にあれば、前回の結果を入力に含めない。
+    // 出力ディレクトリが入力の起点かその上にあるときは、入力がすべて消えてしまうので除かない
+    let canonical_output_dir = fs::canonicalize(&naming.base_dir).ok();
+    let inputs = expand_inputs(&app_params.filepath, app_params.recursive)?
+        .into_iter()
+        .filter(|input| match &canonical_output_dir {
+            Some(output_dir) => !is_previous_output(input, output_dir),
+            None => true,
+        })
+        .collect::<Vec<InputFile>>();
+    if inputs.is_empty() {
+        return Err(AppError::Batch(format!(
+            "no files to process. all inputs are in the output directory {}.",
+            naming.base_dir.display()
+        ))
+        .into());
+    }
+    let total = inputs.len();
+    let items = inputs
+        .into_iter()
+        .enumerate()
+        .map(|(index, input)| BatchItem {
+            input,
+            index: index + 1,
+            total,
+        })
+        .collect::<Vec<BatchItem>>();
+
+    println!("processing {} files ({} at a time)", total, jobs);
+    let width = total.to_string().len();
+    let start = Instant::now();
+    let outcomes = run_batch(
+        &items,
+        jobs,
+        |item| {
+            process_file(
+                item,
+                naming,
+                pipeline,
+                &app_params.encode,
+                &app_params.metadata,
+            )
+        },
+        |done, outcome| {
+            let input = outcome.item.input.path.display();
+            match &outcome.result {
+                Ok(FileOutcome::Written(output)) => println!(
+                    "[{:>width$}/{}] done {} -> {} ({:.1}s)",
+                    done,
+                    total,
+                    input,
+                    output.display(),
+                    outcome.elapsed.as_secs_f64(),
+                ),
+                Ok(FileOutcome::Skipped(reason)) => {
+                    println!("[{:>width$}/{}] skip {} ({})", done, total, input, reason)
+                }
+                Err(_) => println!("[{:>width$}/{}] FAIL {}", done, total, input),
+            }
+        },
+    );
+    let mut skipped = 0;
+    let mut failures = Vec::<(PathBuf, anyhow::Error)>::new();
+    for outcome in outcomes {
+        match outcome.result {
+            Ok(FileOutcome::Written(_)) => {}
+            Ok(FileOutcome::Skipped(_)) => skipped += 1,
+            Err(err) => failures.push((outcome.item.input.path, err)),
+        }
+    }
+
+    println!(
+        "{} files: {} succeeded, {} skipped, {} failed ({:.1}s)",
+        total,
+        total - skipped - failures.len(),
+        skipped,
+        failures.len(),
+        start.elapsed().as_secs_f64()
+    );
+    if failures.is_empty() {
+        return Ok(());
+    }
+    eprintln!("failed files:");
+    for (input, err) in failures.iter() {
+        eprintln!("  {}: {:#}", input.display(), err);
+    }
+    Err(AppError::Batch(format!("{} of {} files failed.", failures.len(), total)).into())
+}
+
+/// 入力の中にある出力ディレクトリに、前回書き出されたファイルか。
+/// 出力ディレクトリが入力の起点 (指定したディレクトリ、globの固定部分、ファイル自身) かその上にあれば除かない。
+fn is_previous_output(input: &InputFile, output_dir: &Path) -> bool {
+    let (Ok(path), Ok(root)) = (fs::canonicalize(&input.path), fs::canonicalize(&input.root))
+    else {
+        return false;
+    };
+    path.starts_with(output_dir) && !root.starts_with(output_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `dir`の下に空のファイルを作る。
+    fn touch(dir: &Path, names: &[&str]) {
+        for name in names {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+    }
+
+    fn remaining(inputs: &[PathBuf], output_dir: &Path) -> Vec<PathBuf> {
+        let output_dir = fs::canonicalize(output_dir).unwrap();
+        let mut remaining = expand_inputs(inputs, true)
+            .unwrap()
+            .into_iter()
+            .filter(|input| !is_previous_output(input, &output_dir))
+            .map(|input| input.path.file_name().unwrap().into())
+            .collect::<Vec<PathBuf>>();
+        remaining.sort();
+        remaining
+    }
+
+    #[test]
+    fn excludes_previous_outputs() {
+        let dir = std::env::temp_dir().join(format!("previous-outputs-{}", std::process::id()));
+        touch(&dir, &["a.png", "sub/b.png", "filtered/a_filtered.png"]);
+        let output_dir = dir.join("filtered");
+        let expected = [PathBuf::from("a.png"), PathBuf::from("b.png")];
+        // globとディレクトリのどちらでも前回の結果は除く
+        assert_eq!(remaining(&[dir.join("**/*.png")], &output_dir), expected);
+        assert_eq!(remaining(std::slice::from_ref(&dir), &output_dir), expected);
+        // 名前を指定したファイルは除かない
+        let named = output_dir.join("a_filtered.png");
+        assert_eq!(
+            remaining(&[named], &output_dir),
+            [PathBuf::from("a_filtered.png")]
+        );
+        // 出力ディレクトリが入力の起点の上にあれば除かない
+        assert_eq!(
+            remaining(&[dir.join("sub/*.png")], &dir),
+            [PathBuf::from("b.png")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
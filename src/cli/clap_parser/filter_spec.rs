@@ -0,0 +1,272 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::ValueEnum;
+use serde_yaml::{Mapping, Value};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::cli::interactive::input::RectInfo;
+use my_img_utilities::filter::{registry::parse_option_value, AppFilter};
+use my_img_utilities::pipeline::FilterProcess;
+use my_img_utilities::process::{BlendMode, BlendOptions};
+use my_img_utilities::region::Region;
+
+/// `--filter`の値を解釈する。書式は`name[:key=value,...]@region`。
+/// オプションにはフィルタ固有のものに加えて、`feather` (ピクセル数)、
+/// `opacity` (0.0 - 1.0 または`60%`)、`blend` (合成モード) を書ける。
+/// regionは次のいずれか。
+///
+/// - `x,y,width,height` (矩形)
+/// - `ellipse:x,y,width,height`
+/// - `rounded:x,y,width,height,radius`
+/// - `polygon:x1,y1,x2,y2,x3,y3,...`
+/// - `mask:path` (マスク画像の白いピクセル)
+///
+/// 例: `gaussian:window=10,sigma=5,feather=8@ellipse:10,20,300,200`
+pub fn parse_filter_spec(spec: &str) -> Result<FilterProcess> {
+    let (filter_part, region_part) = spec
+        .split_once('@')
+        .ok_or_else(|| anyhow!("missing region (expected `@x,y,width,height`)."))?;
+    let (name, options_part) = filter_part.split_once(':').unwrap_or((filter_part, ""));
+    let mut options = parse_options(options_part)?;
+    let blend = take_blend_options(&mut options)?;
+    let filter = build_filter(name.trim(), &options)?;
+    let region = parse_region(region_part)?;
+    let process = FilterProcess::new(filter, region, blend);
+    process.validate()?;
+    Ok(process)
+}
+
+/// `key=value`をカンマ区切りで並べたものを分解する。
+fn parse_options(s: &str) -> Result<Vec<(String, String)>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|pair| {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("option `{}` must be written as key=value.", pair))?;
+            Ok((key.trim().to_ascii_lowercase(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// オプションから`feather`、`opacity`、`blend`を取り除き、混ぜ方として返す。
+fn take_blend_options(options: &mut Vec<(String, String)>) -> Result<BlendOptions> {
+    let mut blend = BlendOptions::default();
+    let mut rest = Vec::new();
+    for (key, value) in options.drain(..) {
+        match key.as_str() {
+            "feather" => blend.feather = parse_value(&key, &value)?,
+            "opacity" => blend.opacity = parse_opacity(&value)?,
+            "blend" => {
+                blend.mode = BlendMode::from_str(&value, true).map_err(|_| {
+                    let modes = BlendMode::value_variants()
+                        .iter()
+                        .map(BlendMode::to_string)
+                        .collect::<Vec<String>>();
+                    anyhow!(
+                        "invalid value for `blend`: {} (expected {})",
+                        value,
+                        modes.join(", ")
+                    )
+                })?
+            }
+            _ => rest.push((key, value)),
+        }
+    }
+    *options = rest;
+    Ok(blend)
+}
+
+/// `0.6`または`60%`を割合として読む。
+fn parse_opacity(value: &str) -> Result<f64> {
+    match value.strip_suffix('%') {
+        Some(percent) => Ok(parse_value::<f64>("opacity", percent.trim())? / 100.0),
+        None => parse_value("opacity", value),
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T>
+where
+    <T as FromStr>::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .parse::<T>()
+        .with_context(|| format!("invalid value for `{}`: {}", key, value))
+}
+
+/// フィルタ名とオプションからレジストリに登録されたフィルタを組み立てる。
+/// 指定されなかったオプションはデフォルト値になる。
+fn build_filter(name: &str, options: &[(String, String)]) -> Result<AppFilter> {
+    let options = options
+        .iter()
+        .map(|(key, value)| (Value::from(key.as_str()), parse_option_value(value)))
+        .collect::<Mapping>();
+    AppFilter::from_options(name, Value::Mapping(options))
+}
+
+/// `@`の後ろを領域として解釈する。形の指定がなければ矩形とみなす。
+fn parse_region(s: &str) -> Result<Region> {
+    let (shape, values) = s.split_once(':').unwrap_or(("rect", s));
+    let region = match shape.trim().to_ascii_lowercase().as_str() {
+        "rect" => parse_rect(values)?.into(),
+        "ellipse" => {
+            let (x, y, width, height) = parse_rect(values)?.0;
+            Region::Ellipse {
+                x,
+                y,
+                width,
+                height,
+            }
+        }
+        "rounded" | "rounded_rect" => {
+            let values = parse_numbers::<u32>("rounded rectangle", values)?;
+            let [x, y, width, height, radius] = values[..] else {
+                bail!("rounded rectangle must have 5 numbers (x,y,width,height,radius).");
+            };
+            Region::RoundedRect {
+                x,
+                y,
+                width,
+                height,
+                radius,
+            }
+        }
+        "polygon" => {
+            let values = parse_numbers::<i32>("polygon", values)?;
+            ensure!(
+                values.len() >= 6 && values.len() % 2 == 0,
+                "polygon must have at least 3 points (x1,y1,x2,y2,x3,y3,...)."
+            );
+            Region::Polygon {
+                points: values.chunks(2).map(|v| [v[0], v[1]]).collect(),
+            }
+        }
+        "mask" => Region::Mask {
+            path: PathBuf::from(values),
+        },
+        _ => bail!(
+            "unknown region shape: {} (expected ellipse, rounded, polygon or mask)",
+            shape
+        ),
+    };
+    Ok(region)
+}
+
+/// `x,y,width,height`を矩形情報として解釈する。
+fn parse_rect(s: &str) -> Result<RectInfo> {
+    let values = parse_numbers::<u32>("rectangle", s)?;
+    ensure!(
+        values.len() == 4,
+        "rectangle must have 4 numbers (x,y,width,height)."
+    );
+    Ok(RectInfo((values[0], values[1], values[2], values[3])))
+}
+
+fn parse_numbers<T: FromStr>(key: &str, s: &str) -> Result<Vec<T>>
+where
+    <T as FromStr>::Err: std::error::Error + Send + Sync + 'static,
+{
+    s.split(',').map(|v| parse_value(key, v.trim())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_specs() {
+        let cases = [
+            ("grayscale@1,2,3,4", "Grayscale @ rect (x=1 y=2 width=3 height=4)"),
+            (
+                "Grayscale @ 1, 2, 3, 4",
+                "Grayscale @ rect (x=1 y=2 width=3 height=4)",
+            ),
+            (
+                "gaussian:sigma=2,feather=8,opacity=60%@ellipse:10,20,30,40",
+                "Gaussian (window_size=auto (6), sigma=2) @ ellipse (x=10 y=20 width=30 height=40) \
+                 (feather=8px opacity=60% blend=normal)",
+            ),
+            (
+                "mosaic:size=4,opacity=0.5,blend=multiply@rounded:0,0,10,10,3",
+                "Mosaic (size=4) @ rounded rect (x=0 y=0 width=10 height=10 radius=3) \
+                 (feather=0px opacity=50% blend=multiply)",
+            ),
+            (
+                "grayscale@polygon:0,0,10,0,5,5",
+                "Grayscale @ polygon [(0, 0), (10, 0), (5, 5)]",
+            ),
+            ("grayscale@mask:m.png", "Grayscale @ mask (m.png)"),
+        ];
+        for (spec, expected) in cases {
+            let process =
+                parse_filter_spec(spec).unwrap_or_else(|err| panic!("{}: {:#}", spec, err));
+            assert_eq!(process.to_string(), expected, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        let cases = [
+            ("grayscale", "missing region"),
+            ("grayscale@1,2,3", "rectangle must have 4 numbers"),
+            ("grayscale@1,2,0,4", "width and height must be positive"),
+            ("grayscale@1,2,x,4", "invalid value for `rectangle`"),
+            (
+                "grayscale@rounded:1,2,3,4",
+                "rounded rectangle must have 5 numbers",
+            ),
+            (
+                "grayscale@rounded:1,2,3,4,5,6",
+                "rounded rectangle must have 5 numbers",
+            ),
+            (
+                "grayscale@polygon:0,0,1,1",
+                "polygon must have at least 3 points",
+            ),
+            (
+                "grayscale@polygon:0,0,1,1,2",
+                "polygon must have at least 3 points",
+            ),
+            ("grayscale@star:1,2,3,4", "unknown region shape: star"),
+            ("unknown@1,2,3,4", "unknown filter: unknown"),
+            (
+                "gaussian:sigma@1,2,3,4",
+                "option `sigma` must be written as key=value",
+            ),
+            ("gaussian:bogus=1@1,2,3,4", "unknown field `bogus`"),
+            (
+                "grayscale:feather=-1@1,2,3,4",
+                "invalid value for `feather`",
+            ),
+            (
+                "grayscale:opacity=abc%@1,2,3,4",
+                "invalid value for `opacity`",
+            ),
+            (
+                "grayscale:opacity=150%@1,2,3,4",
+                "opacity must be between 0 and 1",
+            ),
+            (
+                "grayscale:blend=foo@1,2,3,4",
+                "invalid value for `blend`: foo",
+            ),
+        ];
+        for (spec, expected) in cases {
+            match parse_filter_spec(spec) {
+                Ok(process) => panic!("{}: parsed as {}", spec, process),
+                Err(err) => {
+                    let message = format!("{:#}", err);
+                    assert!(message.contains(expected), "{}: {}", spec, message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_mosaic_size_beyond_u32() {
+        // u32に切り詰めると0になり、0除算で落ちていた
+        assert!(parse_filter_spec("mosaic:size=4294967296@0,0,10,10").is_err());
+        assert!(parse_filter_spec("mosaic:size=4294967295@0,0,10,10").is_ok());
+    }
+}
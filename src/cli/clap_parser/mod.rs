@@ -1 +1,2 @@
+pub mod filter_spec;
 pub mod parser;
@@ -1,17 +1,161 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Args, Parser, ValueEnum};
 
-/// Convert image file to webp format.
+use super::filter_spec::parse_filter_spec;
+use my_img_utilities::metadata::MetadataPolicy;
+use my_img_utilities::naming::{ConflictPolicy, OutputTemplate};
+use my_img_utilities::output::{EncodeOptions, OutputFormat, PngCompression};
+use my_img_utilities::pipeline::FilterProcess;
+use my_img_utilities::process::BorderPolicy;
+
+/// Apply image filters to parts of an image and save it as JPEG, PNG, WebP, TIFF, BMP or QOI.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct AppArgs {
-    /// target file.
-    #[arg(short, long)]
-    pub filepath: Option<PathBuf>,
+    /// target files, directories or glob patterns (quote patterns to keep the shell from expanding them).
+    /// more than one input, a directory or a pattern processes the files in a batch.
+    #[arg(short, long, num_args = 1..)]
+    pub filepath: Vec<PathBuf>,
+    /// output file when processing a single file.
     #[arg(short, long)]
     pub output: Option<PathBuf>,
-    // /// specify filter type
-    // #[arg(short, long)]
-    // pub filter_type: Option<u32>,
+    /// directory to write the results of a batch to. subdirectories of input directories are kept.
+    #[arg(long, value_name = "DIR", conflicts_with = "output")]
+    pub output_dir: Option<PathBuf>,
+    /// name of the outputs when --output is not given, relative to the output directory
+    /// (the current directory for a single file). placeholders: {stem} {ext} {dir} {index} {date}
+    /// {width} {height} {filters}. default: {dir}/{stem}_filtered.{ext}
+    #[arg(long, value_name = "TEMPLATE", conflicts_with = "output")]
+    pub output_template: Option<OutputTemplate>,
+    /// what to do when an output file already exists. defaults to auto-number.
+    #[arg(long, value_enum, value_name = "POLICY")]
+    pub on_conflict: Option<ConflictPolicy>,
+    /// also process files in subdirectories of input directories.
+    #[arg(long)]
+    pub recursive: bool,
+    /// number of files processed at the same time in a batch. defaults to the number of CPUs, up to 4.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub jobs: Option<u16>,
+    /// YAML recipe file. loaded if it exists, otherwise the interactive session is saved to it.
+    #[arg(short, long)]
+    pub recipe: Option<PathBuf>,
+    /// edit the filters of the loaded recipe (append, remove, reorder) before running.
+    #[arg(short, long, requires = "recipe", conflicts_with = "no_interactive")]
+    pub edit: bool,
+    /// filter to apply (repeatable). format: name[:key=value,...]@region
+    /// (see --list-filters for names and options)
+    /// region: x,y,width,height | ellipse:x,y,width,height | rounded:x,y,width,height,radius
+    /// | polygon:x1,y1,x2,y2,x3,y3,... | mask:path
+    /// options may also include feather=<px>, opacity=<0.0-1.0 or N%> and blend=<MODE>
+    /// (normal, multiply, screen, overlay, soft-light, difference, luminosity, color).
+    /// e.g. gaussian:window=10,sigma=5,feather=8,opacity=60%@ellipse:10,20,300,200
+    #[arg(long = "filter", value_name = "SPEC", value_parser = parse_filter_spec)]
+    pub filters: Vec<FilterProcess>,
+    /// list the available filters and their options, then exit.
+    #[arg(long)]
+    pub list_filters: bool,
+    /// never prompt. missing parameters are reported as errors.
+    #[arg(long)]
+    pub no_interactive: bool,
+    /// how filters read pixels beyond the image edge: clamp, mirror, wrap or constant[:#rrggbb[aa]].
+    #[arg(long, value_name = "POLICY")]
+    pub border: Option<BorderPolicy>,
+    /// number of threads used by filters. defaults to the number of CPUs. results do not depend on it.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub threads: Option<u16>,
+    #[command(flatten)]
+    pub encode: EncodeArgs,
+    #[command(flatten)]
+    pub metadata: MetadataArgs,
+}
+
+/// 出力フォーマットに関する引数。指定されたものだけレシピの設定を上書きする。
+#[derive(Args, Debug)]
+pub struct EncodeArgs {
+    /// output format. guessed from the output extension if omitted.
+    #[arg(long, value_enum)]
+    pub format: Option<OutputFormat>,
+    /// JPEG quality (1-100).
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub jpeg_quality: Option<u8>,
+    /// PNG compression level.
+    #[arg(long, value_enum)]
+    pub png_compression: Option<PngCompression>,
+    /// encode WebP losslessly.
+    #[arg(long)]
+    pub webp_lossless: bool,
+    /// lossy WebP quality (0-100).
+    #[arg(long, conflicts_with = "webp_lossless")]
+    pub webp_quality: Option<f32>,
+}
+impl EncodeArgs {
+    pub fn apply(&self, options: &mut EncodeOptions) {
+        if let Some(format) = self.format {
+            options.format = Some(format);
+        }
+        if let Some(quality) = self.jpeg_quality {
+            options.jpeg_quality = quality;
+        }
+        if let Some(compression) = self.png_compression {
+            options.png_compression = compression;
+        }
+        if self.webp_lossless {
+            options.webp_lossless = true;
+        }
+        if let Some(quality) = self.webp_quality {
+            options.webp_quality = quality;
+            options.webp_lossless = false;
+        }
+    }
+}
+
+/// `--strip`で取り除く対象。
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum StripTarget {
+    /// all EXIF/XMP metadata.
+    All,
+    /// GPS location. XMP is dropped as well since it may also contain it.
+    Gps,
+    /// camera and lens serial numbers (and maker notes). XMP is dropped as well since it may also contain them.
+    Serials,
+}
+
+/// メタデータの扱いに関する引数。何も指定しなければレシピの設定（デフォルトはすべて破棄）に従う。
+#[derive(Args, Debug)]
+pub struct MetadataArgs {
+    /// keep EXIF/XMP metadata of the input.
+    #[arg(long)]
+    pub keep_metadata: bool,
+    /// strip metadata (repeatable). `gps` and `serials` keep the rest of the EXIF but drop XMP.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "TARGET",
+        conflicts_with = "keep_metadata"
+    )]
+    pub strip: Vec<StripTarget>,
+}
+impl MetadataArgs {
+    pub fn apply(&self, policy: &mut MetadataPolicy) {
+        if self.keep_metadata {
+            *policy = MetadataPolicy {
+                keep: true,
+                ..Default::default()
+            };
+        }
+        // allは他の指定より優先する
+        if self.strip.iter().any(|t| matches!(t, StripTarget::All)) {
+            *policy = MetadataPolicy::default();
+            return;
+        }
+        for target in self.strip.iter() {
+            policy.keep = true;
+            match target {
+                StripTarget::All => {}
+                StripTarget::Gps => policy.strip_gps = true,
+                StripTarget::Serials => policy.strip_serials = true,
+            }
+        }
+    }
 }
@@ -1,72 +1,174 @@
-use inquire::{error::InquireResult, Confirm, CustomType, Select, Text};
+use anyhow::{bail, ensure, Context, Result};
+use clap::ValueEnum;
+use inquire::{error::InquireResult, validator::Validation, Confirm, CustomType, Select, Text};
+use serde_derive::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
 use std::fmt::Display;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use crate::cli::clap_parser::parser::AppArgs;
-use crate::filter::prelude::*;
-use crate::filter::{AppFilter, AppFilterType};
+use crate::cli::recipe::{load_recipe, save_recipe};
+use my_img_utilities::batch::is_glob;
+use my_img_utilities::error::AppError;
+use my_img_utilities::filter::registry::{self, FilterEntry};
+use my_img_utilities::filter::schema::{OptionKind, OptionSpec};
+use my_img_utilities::filter::AppFilter;
+use my_img_utilities::metadata::MetadataPolicy;
+use my_img_utilities::naming::{ConflictPolicy, OutputNaming, OutputTemplate};
+use my_img_utilities::output::EncodeOptions;
+use my_img_utilities::pipeline::FilterProcess;
+use my_img_utilities::process::{BlendMode, BlendOptions, BorderPolicy};
+use my_img_utilities::region::Region;
 
 use super::autocompleter::FilePathCompleter;
 
-#[derive(Debug, Clone)]
-pub struct FilterProcess {
-    pub filter: AppFilter,
-    pub x: u32,
-    pub y: u32,
-    pub width: u32,
-    pub height: u32,
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AppParams {
+    /// 入力のファイル、ディレクトリ、globパターン。1つならレシピにはパスをそのまま書く。
+    #[serde(with = "one_or_many")]
+    pub filepath: Vec<PathBuf>,
+    /// 1つのファイルを処理するときの出力先。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<PathBuf>,
+    /// バッチ処理の結果を書き出すディレクトリ。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_dir: Option<PathBuf>,
+    /// 入力のディレクトリのサブディレクトリもたどるか。
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub recursive: bool,
+    /// `output`がないときの出力ファイル名。指定がなければ`naming::DEFAULT_TEMPLATE`を使う。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_template: Option<OutputTemplate>,
+    /// 出力先にすでにファイルがあるときの扱い。
+    #[serde(default, skip_serializing_if = "is_auto_number")]
+    pub on_conflict: ConflictPolicy,
+    pub processes: Vec<FilterProcess>,
+    #[serde(default)]
+    pub encode: EncodeOptions,
+    #[serde(default)]
+    pub metadata: MetadataPolicy,
+    /// フィルタが画像の外を読むときの扱い。
+    #[serde(default)]
+    pub border: BorderPolicy,
 }
-impl FilterProcess {
-    pub fn new(filter: AppFilter, rect_info: RectInfo) -> Self {
-        let rect = rect_info.0;
-        FilterProcess {
-            filter,
-            x: rect.0,
-            y: rect.1,
-            width: rect.2,
-            height: rect.3,
-        }
+impl AppParams {
+    /// 複数のファイルをまとめて処理するか。入力が複数あるか、ディレクトリかglobパターンのときになる。
+    pub fn is_batch(&self) -> bool {
+        self.output_dir.is_some() || is_batch_input(&self.filepath)
+    }
+    /// 出力先の決め方。テンプレートはバッチ処理なら出力ディレクトリ、そうでなければ現在のディレクトリの下で使う。
+    pub fn naming(&self) -> OutputNaming {
+        OutputNaming::new(
+            self.output.clone(),
+            self.output_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(".")),
+            self.output_template.clone().unwrap_or_default(),
+            self.on_conflict,
+        )
     }
 }
-impl std::fmt::Display for FilterProcess {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self.filter {
-            AppFilter::Gaussian(filter) => filter.fmt(f),
-            AppFilter::GrayScale(filter) => filter.fmt(f),
-            AppFilter::Kuwahara(filter) => filter.fmt(f),
-            AppFilter::Mosaic(filter) => filter.fmt(f),
-            AppFilter::Truncate(filter) => filter.fmt(f),
-        }
+/// 入力の指定が複数のファイルを表しうるか。
+fn is_batch_input(filepath: &[PathBuf]) -> bool {
+    match filepath {
+        [path] => is_glob(path) || path.is_dir(),
+        _ => true,
     }
 }
-
-#[derive(Debug)]
-pub struct AppParams {
-    pub filepath: PathBuf,
-    pub output: PathBuf,
-    pub processes: Vec<FilterProcess>,
+fn is_false(value: &bool) -> bool {
+    !value
+}
+fn is_auto_number(value: &ConflictPolicy) -> bool {
+    *value == ConflictPolicy::AutoNumber
 }
 impl std::fmt::Display for AppParams {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let filter_str = self
-            .processes
+        let filter_str =
+            self.processes
+                .iter()
+                .enumerate()
+                .fold("".to_string(), |prev, (idx, next)| {
+                    format!(
+                        "{}    {:>2}. {}
+",
+                        prev,
+                        idx + 1,
+                        next
+                    )
+                });
+        let format_str = match (&self.output, self.encode.format) {
+            (Some(output), _) => match self.encode.resolve_format(output) {
+                Ok(format) => self.encode.describe(format),
+                Err(_) => "unknown".to_string(),
+            },
+            (None, Some(format)) => self.encode.describe(format),
+            (None, None) => "same as each input (jpg if it cannot be written)".to_string(),
+        };
+        let filepath_str = self
+            .filepath
             .iter()
-            .enumerate()
-            .fold("".to_string(), |prev, (idx, next)| {
-                format!("{}    {:>2}. {}\n", prev, idx + 1, next)
-            });
+            .map(|path| path.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let template = self.output_template.clone().unwrap_or_default();
+        let output_str = match (&self.output, &self.output_dir) {
+            (Some(output), _) => output.to_string_lossy().to_string(),
+            (None, Some(output_dir)) => format!(
+                "{}{}{} (batch)",
+                output_dir.to_string_lossy(),
+                std::path::MAIN_SEPARATOR,
+                template
+            ),
+            (None, None) => template.to_string(),
+        };
+        let recursive_str = if self.recursive { " (recursive)" } else { "" };
         write!(
             f,
-            "> filepath: {}\n> output  : {}\n> filters :\n{}",
-            self.filepath.to_string_lossy(),
-            self.output.to_string_lossy(),
+            "> filepath: {}{}\n> output  : {}\n> conflict: {}\n> format  : {}\n> metadata: {}\n> border  : {}\n> filters :\n{}",
+            filepath_str,
+            recursive_str,
+            output_str,
+            self.on_conflict,
+            format_str,
+            self.metadata,
+            self.border,
             filter_str
         )
     }
 }
 
+/// 1つのパスと、パスの配列のどちらでも読み書きできるようにする。
+mod one_or_many {
+    use serde::{Deserialize as _, Deserializer, Serialize as _, Serializer};
+    use serde_derive::Deserialize;
+    use std::path::PathBuf;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+
+    pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+        match paths {
+            [path] => path.serialize(serializer),
+            paths => paths.serialize(serializer),
+        }
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<PathBuf>, D::Error> {
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(path) => vec![path],
+            OneOrMany::Many(paths) => paths,
+        })
+    }
+}
+
 /// 矩形情報を表す。(x, y, width, height)で、(x, y)は矩形のtop-leftを配置する。
 #[derive(Debug, Clone, Copy)]
 pub struct RectInfo(pub (u32, u32, u32, u32));
@@ -88,6 +190,17 @@ impl FromStr for RectInfo {
         }
     }
 }
+impl From<RectInfo> for Region {
+    fn from(value: RectInfo) -> Self {
+        let (x, y, width, height) = value.0;
+        Region::Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
 impl Display for RectInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let value = self.0;
@@ -115,56 +228,60 @@ where
     }
 }
 
-pub fn input_on_console(app_args: &AppArgs) -> InquireResult<AppParams> {
-    // コマンドライン引数に存在しない場合はプロンプトを用いて決定させる。
-    let filepath = match &app_args.filepath {
-        Some(filepath) => {
-            let canononicalized = fs::canonicalize(filepath).unwrap();
-            println!("filepath: {}", canononicalized.to_str().unwrap());
-            canononicalized
-        }
-        None => loop {
-            let filepath = Text::new("file path:")
+#[derive(Debug, Clone, Copy)]
+enum RegionShape {
+    Rect,
+    Ellipse,
+    RoundedRect,
+    Polygon,
+    Mask,
+}
+impl RegionShape {
+    fn create_vec() -> Vec<Self> {
+        vec![
+            Self::Rect,
+            Self::Ellipse,
+            Self::RoundedRect,
+            Self::Polygon,
+            Self::Mask,
+        ]
+    }
+}
+impl Display for RegionShape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Rect => "rectangle",
+            Self::Ellipse => "ellipse",
+            Self::RoundedRect => "rounded rectangle",
+            Self::Polygon => "polygon",
+            Self::Mask => "white pixels of a mask image",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// フィルタを適用する領域をプロンプトで入力させる。
+fn prompt_region() -> InquireResult<Region> {
+    let shape = Select::new("region shape:", RegionShape::create_vec()).prompt()?;
+    let rect_info = match shape {
+        RegionShape::Polygon => loop {
+            let points = Text::new("polygon vertices (format: x1 y1 x2 y2 x3 y3 ...):").prompt()?;
+            match parse_polygon(&points) {
+                Ok(points) => return Ok(Region::Polygon { points }),
+                Err(err) => println!("{}", err),
+            }
+        },
+        RegionShape::Mask => loop {
+            let path = Text::new("mask image path:")
                 .with_autocomplete(FilePathCompleter::default())
-                .prompt()
-                .unwrap_or_else(|err| {
-                    println!("{}", err);
-                    std::process::exit(1)
-                });
-            let pathbuf = PathBuf::from(filepath);
-            if pathbuf.exists() {
-                break fs::canonicalize(pathbuf).unwrap();
-            } else {
-                println!("path does not exist.");
+                .prompt()?;
+            let path = PathBuf::from(path);
+            if path.exists() {
+                return Ok(Region::Mask { path });
             }
+            println!("path does not exist.");
         },
-    };
-    let output = match &app_args.output {
-        Some(output) => {
-            let canononicalized = fs::canonicalize(output).unwrap();
-            println!("filepath: {}", canononicalized.to_str().unwrap());
-            canononicalized
-        }
-        None => {
-            let default_path = {
-                let file_stem = filepath.file_stem().unwrap();
-                format!("./{}_filtered.jpg", file_stem.to_str().unwrap())
-            };
-            let filepath = Text::new("output path:")
-                .with_default(&default_path)
-                .prompt()
-                .unwrap_or_else(|err| {
-                    println!("{}", err);
-                    std::process::exit(1)
-                });
-            PathBuf::from(filepath)
-        }
-    };
-    let mut processes = Vec::<FilterProcess>::new();
-    let filter_vec = AppFilterType::create_vec();
-    loop {
-        let filter_type = Select::new("filter type:", filter_vec.clone()).prompt()?;
-        let rect_info = CustomType::new(
+        _ => CustomType::new(
             "specify x, y of top-left, and width and height (format: x y width height):",
         )
         .with_formatter(&|rect_info: RectInfo| {
@@ -172,63 +289,430 @@ pub fn input_on_console(app_args: &AppArgs) -> InquireResult<AppParams> {
             format!("x={} y={} width={} height={}", x, y, width, height)
         })
         .with_error_message("Please type a valid number")
-        .with_help_message("")
         .with_help_message("if the input exceeds max width of height, it clamped automatically.")
-        .prompt()?;
-        let filter = match filter_type {
-            AppFilterType::Gaussian => {
-                let GaussianFilterOption { window_size, sigma } = GaussianFilterOption::default();
-                let window_size =
-                    simple_param_input("input window size (positive integer)", window_size)?;
-                let sigma = simple_param_input("input parameter sigma (float)", sigma)?;
-
-                AppFilter::Gaussian(GaussianFilter::new(GaussianFilterOption::new(
-                    window_size,
-                    sigma,
-                )))
-            }
-
-            AppFilterType::GrayScale => AppFilter::GrayScale(GrayscaleFilter::new()),
-            AppFilterType::Kuwahara => {
-                let KuwaharaFilterOptions { window_size } = KuwaharaFilterOptions::default();
-                let window_size =
-                    simple_param_input("input window size (positive integer)", window_size)?;
-                AppFilter::Kuwahara(KuwaharaFilter::new(KuwaharaFilterOptions::new(window_size)))
-            }
-            AppFilterType::Mosaic => {
-                let MosaicFilterOption { size } = MosaicFilterOption::default();
-                let size = simple_param_input("input window size (positive integer)", size)?;
-                AppFilter::Mosaic(MosaicFilter::new(MosaicFilterOption::new(size)))
-            }
-            AppFilterType::Truncate => {
-                let component =
-                    Select::new("select rgb component to truncate", TruncateComponent::vec())
-                        .prompt()?;
-
-                AppFilter::Truncate(TruncateColorFilter::new(TruncateColorFilterOption::new(
-                    component,
-                )))
+        .prompt()?,
+    };
+    let (x, y, width, height) = rect_info.0;
+    let region = match shape {
+        RegionShape::Ellipse => Region::Ellipse {
+            x,
+            y,
+            width,
+            height,
+        },
+        RegionShape::RoundedRect => {
+            let radius = simple_param_input("input corner radius (integer)", 0u32)?;
+            Region::RoundedRect {
+                x,
+                y,
+                width,
+                height,
+                radius,
             }
-        };
-        processes.push(FilterProcess::new(filter, rect_info));
+        }
+        _ => rect_info.into(),
+    };
+    Ok(region)
+}
+
+/// 空白区切りの`x1 y1 x2 y2 ...`を多角形の頂点列として解釈する。
+fn parse_polygon(s: &str) -> Result<Vec<[i32; 2]>, String> {
+    let values = s
+        .split_whitespace()
+        .map(|v| v.parse::<i32>().map_err(|err| format!("{:?}", err)))
+        .collect::<Result<Vec<i32>, String>>()?;
+    if values.len() < 6 || values.len() % 2 != 0 {
+        return Err(String::from(
+            "polygon needs at least 3 points given as pairs of numbers.",
+        ));
+    }
+    Ok(values.chunks(2).map(|v| [v[0], v[1]]).collect())
+}
 
-        match Confirm::new("add another filter ?")
-            .with_default(false)
-            .prompt()
+/// オプションのスキーマから入力欄を作り、値を入力させる。選択肢のあるものは一覧から選ばせ、
+/// 数は型と範囲をその場で検査する。空欄と`auto`は`None`になる。
+fn prompt_option(spec: &OptionSpec) -> InquireResult<Option<Value>> {
+    let message = format!("{}:", spec.help);
+    let help = format!("{} ({})", spec.name, spec.describe());
+    if let OptionKind::Choice(choices) = spec.kind {
+        let cursor = spec
+            .default
+            .as_ref()
+            .and_then(|default| choices.iter().position(|choice| choice == default))
+            .unwrap_or(0);
+        let choice = Select::new(&message, choices.to_vec())
+            .with_help_message(&help)
+            .with_starting_cursor(cursor)
+            .prompt()?;
+        return Ok(Some(Value::from(choice)));
+    }
+    let default = match &spec.default {
+        Some(default) => default.clone(),
+        None if spec.auto => String::from("auto"),
+        None => String::new(),
+    };
+    let validator_spec = spec.clone();
+    let mut text =
+        Text::new(&message)
+            .with_help_message(&help)
+            .with_validator(move |input: &str| {
+                Ok(match validator_spec.parse(input) {
+                    Ok(_) => Validation::Valid,
+                    Err(err) => Validation::Invalid(err.to_string().into()),
+                })
+            });
+    if !default.is_empty() {
+        text = text.with_default(&default);
+    }
+    let input = text.prompt()?;
+    // 検査済みなので読めないことはない
+    Ok(spec.parse(&input).unwrap_or(None))
+}
+
+/// 登録されたオプションを1つずつ入力させてフィルタを作る。
+/// オプションの組み合わせが不正ならはじめから入力し直す。
+fn prompt_filter_options(entry: &FilterEntry) -> InquireResult<AppFilter> {
+    loop {
+        let mut options = Mapping::new();
+        for spec in entry.options.iter() {
+            if let Some(value) = prompt_option(spec)? {
+                options.insert(spec.name.into(), value);
+            }
+        }
+        match entry
+            .build(Value::Mapping(options))
+            .and_then(|filter| filter.validate().map(|_| filter))
         {
-            Ok(true) => continue,
-            Ok(false) => break,
-            Err(err) => {
-                println!("{}", err);
-                std::process::exit(1);
+            Ok(filter) => break Ok(filter),
+            Err(err) => println!("{:#}", err),
+        }
+    }
+}
+
+/// フィルタの種類、適用範囲、オプションをプロンプトで入力させる。
+fn prompt_filter_process() -> InquireResult<FilterProcess> {
+    let entry = Select::new("filter type:", registry::entries()).prompt()?;
+    let region = prompt_region()?;
+    let filter = prompt_filter_options(&entry)?;
+    let mode = Select::new("blend mode:", BlendMode::value_variants().to_vec()).prompt()?;
+    let BlendOptions {
+        feather, opacity, ..
+    } = BlendOptions::default();
+    let feather = simple_param_input(
+        "input feather radius in pixels (0 for a hard edge)",
+        feather,
+    )?;
+    let opacity = loop {
+        let opacity = simple_param_input("input opacity (0.0 - 1.0)", opacity)?;
+        if (0.0..=1.0).contains(&opacity) {
+            break opacity;
+        }
+        println!("opacity must be between 0 and 1.");
+    };
+    Ok(FilterProcess::new(
+        filter,
+        region,
+        BlendOptions::new(feather, opacity, mode),
+    ))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RecipeEditAction {
+    Run,
+    Append,
+    Remove,
+    Move,
+}
+impl RecipeEditAction {
+    fn create_vec() -> Vec<Self> {
+        vec![Self::Run, Self::Append, Self::Remove, Self::Move]
+    }
+}
+impl Display for RecipeEditAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Run => "run with these filters",
+            Self::Append => "append a filter",
+            Self::Remove => "remove a filter",
+            Self::Move => "move a filter",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 処理の一覧から1つを選ばせ、そのインデックスを返す。
+fn select_process(message: &str, processes: &[FilterProcess]) -> InquireResult<usize> {
+    let options = processes
+        .iter()
+        .enumerate()
+        .map(|(idx, process)| format!("{:>2}. {}", idx + 1, process))
+        .collect::<Vec<String>>();
+    Ok(Select::new(message, options).raw_prompt()?.index)
+}
+
+/// レシピから読み込んだフィルタ処理の追加・削除・並べ替えを行う。変更があればtrueを返す。
+fn edit_processes(app_params: &mut AppParams) -> InquireResult<bool> {
+    let mut modified = false;
+    loop {
+        println!("{}", app_params);
+        let processes = &mut app_params.processes;
+        match Select::new("recipe action:", RecipeEditAction::create_vec()).prompt()? {
+            RecipeEditAction::Run if processes.is_empty() => {
+                println!("at least one filter is required.");
+            }
+            RecipeEditAction::Run => break Ok(modified),
+            RecipeEditAction::Append => {
+                processes.push(prompt_filter_process()?);
+                modified = true;
+            }
+            RecipeEditAction::Remove if processes.is_empty() => {
+                println!("there is no filter to remove.");
+            }
+            RecipeEditAction::Remove => {
+                let idx = select_process("filter to remove:", processes)?;
+                processes.remove(idx);
+                modified = true;
+            }
+            RecipeEditAction::Move if processes.len() < 2 => {
+                println!("there is nothing to reorder.");
+            }
+            RecipeEditAction::Move => {
+                let from = select_process("filter to move:", processes)?;
+                let message = format!("move to position (1-{})", processes.len());
+                let to = loop {
+                    let to = simple_param_input(&message, from + 1)?;
+                    if (1..=processes.len()).contains(&to) {
+                        break to - 1;
+                    }
+                    println!("position must be between 1 and {}.", processes.len());
+                };
+                let process = processes.remove(from);
+                processes.insert(to, process);
+                modified = true;
             }
         }
     }
+}
+
+/// 1つのファイルを処理するときに、出力パスのプロンプトに出すテンプレート。
+const SINGLE_OUTPUT_TEMPLATE: &str = "{stem}_filtered.{ext}";
+
+/// バッチ処理の出力ディレクトリのデフォルト。
+const DEFAULT_OUTPUT_DIR: &str = "./filtered";
+
+/// 入力の指定を確かめる。ファイルとディレクトリは絶対パスにし、globパターンはそのまま使う。
+fn resolve_input(path: &Path) -> Result<PathBuf> {
+    if is_glob(path) {
+        return Ok(path.to_path_buf());
+    }
+    fs::canonicalize(path).with_context(|| format!("cannot open {}", path.to_string_lossy()))
+}
+
+/// バッチ処理なら出力ディレクトリを決める。指定がなければデフォルトを使う。
+/// 1つのファイルで出力ファイルの指定がなければ、読み込んだあとにテンプレートから決める。
+fn resolve_outputs(app_params: &mut AppParams) -> Result<()> {
+    if app_params.is_batch() {
+        ensure!(
+            app_params.output.is_none(),
+            AppError::InvalidOption(
+                "output cannot be used with multiple inputs. use output_dir (--output-dir) instead."
+                    .to_string()
+            )
+        );
+        app_params
+            .output_dir
+            .get_or_insert_with(|| PathBuf::from(DEFAULT_OUTPUT_DIR));
+    }
+    Ok(())
+}
+
+pub fn input_on_console(app_args: &AppArgs) -> Result<AppParams> {
+    // レシピファイルが存在すればそれを使い、入出力パスはコマンドライン引数で上書きできる。
+    // `--filter`で指定されたフィルタはレシピの処理の後ろに追加する。
+    if let Some(recipe) = app_args.recipe.as_ref().filter(|path| path.exists()) {
+        let mut app_params = load_recipe(recipe)?;
+        println!("recipe loaded: {}", recipe.to_string_lossy());
+        if !app_args.filepath.is_empty() {
+            app_params.filepath = app_args.filepath.clone();
+        }
+        if let Some(output) = &app_args.output {
+            app_params.output = Some(output.clone());
+            app_params.output_dir = None;
+        }
+        if let Some(output_template) = &app_args.output_template {
+            app_params.output_template = Some(output_template.clone());
+            app_params.output = None;
+        }
+        if let Some(on_conflict) = app_args.on_conflict {
+            app_params.on_conflict = on_conflict;
+        }
+        if let Some(output_dir) = &app_args.output_dir {
+            app_params.output_dir = Some(output_dir.clone());
+            app_params.output = None;
+        }
+        app_params.recursive |= app_args.recursive;
+        app_params
+            .processes
+            .extend(app_args.filters.iter().cloned());
+        app_args.encode.apply(&mut app_params.encode);
+        app_args.metadata.apply(&mut app_params.metadata);
+        if let Some(border) = app_args.border {
+            app_params.border = border;
+        }
+        resolve_outputs(&mut app_params)?;
+        if app_args.edit
+            && edit_processes(&mut app_params)?
+            && Confirm::new("save changes to the recipe ?")
+                .with_default(true)
+                .prompt()?
+        {
+            save_recipe(recipe, &app_params)?;
+            println!("recipe saved: {}", recipe.to_string_lossy());
+        }
+        return Ok(app_params);
+    }
+    let mut encode = EncodeOptions::default();
+    app_args.encode.apply(&mut encode);
+    let mut metadata = MetadataPolicy::default();
+    app_args.metadata.apply(&mut metadata);
+    // コマンドライン引数に存在しない場合はプロンプトを用いて決定させる。
+    // 非対話モードではプロンプトを出さずにエラーとする。
+    let filepath = if !app_args.filepath.is_empty() {
+        let filepath = app_args
+            .filepath
+            .iter()
+            .map(|path| resolve_input(path))
+            .collect::<Result<Vec<PathBuf>>>()?;
+        for path in filepath.iter() {
+            println!("filepath: {}", path.to_string_lossy());
+        }
+        filepath
+    } else if app_args.no_interactive {
+        bail!("--filepath is required in non-interactive mode.")
+    } else {
+        loop {
+            let filepath = Text::new("file path (file, directory or glob pattern):")
+                .with_autocomplete(FilePathCompleter::default())
+                .prompt()?;
+            match resolve_input(Path::new(&filepath)) {
+                Ok(path) => break vec![path],
+                Err(_) => println!("path does not exist."),
+            }
+        }
+    };
+    let batch = app_args.output_dir.is_some() || is_batch_input(&filepath);
+    ensure!(
+        !(batch && app_args.output.is_some()),
+        AppError::InvalidOption(
+            "--output cannot be used with multiple inputs. use --output-dir instead.".to_string()
+        )
+    );
+    let mut output_template = app_args.output_template.clone();
+    let (output, output_dir) = match (&app_args.output, &app_args.output_dir) {
+        (Some(output), _) => {
+            println!("output  : {}", output.to_string_lossy());
+            (Some(output.clone()), None)
+        }
+        (None, Some(output_dir)) => {
+            println!("output  : {}", output_dir.to_string_lossy());
+            (None, Some(output_dir.clone()))
+        }
+        (None, None) if batch && app_args.no_interactive => {
+            (None, Some(PathBuf::from(DEFAULT_OUTPUT_DIR)))
+        }
+        (None, None) if batch => {
+            let output_dir = Text::new("output directory:")
+                .with_default(DEFAULT_OUTPUT_DIR)
+                .prompt()?;
+            (None, Some(PathBuf::from(output_dir)))
+        }
+        (None, None) if app_args.no_interactive || output_template.is_some() => (None, None),
+        (None, None) => {
+            // プレースホルダを含めばテンプレートとして、含まなければ出力パスとして使う
+            let output = Text::new("output path:")
+                .with_default(SINGLE_OUTPUT_TEMPLATE)
+                .with_help_message(
+                    "placeholders: {stem} {ext} {index} {date} {width} {height} {filters}",
+                )
+                .with_validator(|input: &str| {
+                    Ok(match OutputTemplate::from_str(input) {
+                        Ok(_) => Validation::Valid,
+                        Err(err) => Validation::Invalid(err.to_string().into()),
+                    })
+                })
+                .prompt()?;
+            let template = OutputTemplate::from_str(&output)?;
+            if template.has_placeholders() {
+                output_template = Some(template);
+                (None, None)
+            } else {
+                (Some(PathBuf::from(output)), None)
+            }
+        }
+    };
+    let processes = if !app_args.filters.is_empty() {
+        app_args.filters.clone()
+    } else if app_args.no_interactive {
+        bail!("at least one --filter is required in non-interactive mode.")
+    } else {
+        let mut processes = Vec::<FilterProcess>::new();
+        loop {
+            processes.push(prompt_filter_process()?);
+            if !Confirm::new("add another filter ?")
+                .with_default(false)
+                .prompt()?
+            {
+                break;
+            }
+        }
+        processes
+    };
 
     let app_params = AppParams {
         filepath,
         output,
+        output_dir,
+        recursive: app_args.recursive,
+        output_template,
+        on_conflict: app_args.on_conflict.unwrap_or_default(),
         processes,
+        encode,
+        metadata,
+        border: app_args.border.unwrap_or_default(),
+    };
+    // レシピファイルが指定されていれば今回の入力内容を保存しておく。
+    // 指定がなければ対話モードの場合のみ保存するか尋ねる。
+    let recipe = match &app_args.recipe {
+        Some(recipe) => Some(recipe.clone()),
+        None if app_args.no_interactive => None,
+        None => {
+            if Confirm::new("save this session as a recipe ?")
+                .with_default(false)
+                .prompt()?
+            {
+                let default_path = {
+                    let file_stem = match app_params.is_batch() {
+                        true => "batch".into(),
+                        false => app_params.filepath[0]
+                            .file_stem()
+                            .unwrap_or_default()
+                            .to_string_lossy(),
+                    };
+                    format!("./{}_recipe.yaml", file_stem)
+                };
+                let recipe = Text::new("recipe path:")
+                    .with_default(&default_path)
+                    .prompt()?;
+                Some(PathBuf::from(recipe))
+            } else {
+                None
+            }
+        }
     };
+    if let Some(recipe) = recipe {
+        save_recipe(&recipe, &app_params)?;
+        println!("recipe saved: {}", recipe.to_string_lossy());
+        println!("(run again with `--recipe <path> --edit` to modify it)");
+    }
     Ok(app_params)
 }
@@ -1,2 +1,4 @@
+pub mod batch;
 pub mod clap_parser;
 pub mod interactive;
+pub mod recipe;
@@ -0,0 +1,50 @@
+use anyhow::{ensure, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use super::interactive::input::AppParams;
+use my_img_utilities::error::AppError;
+
+/// YAML形式のレシピファイルを読み込み、内容を検査してAppParamsとして返す。
+pub fn load_recipe<P: AsRef<Path>>(path: P) -> Result<AppParams> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .with_context(|| format!("failed to open recipe file: {}", path.display()))?;
+    let app_params: AppParams =
+        serde_yaml::from_reader(BufReader::new(file)).with_context(|| {
+            AppError::InvalidOption(format!("invalid recipe file: {}", path.display()))
+        })?;
+    validate_recipe(&app_params)
+        .with_context(|| format!("invalid recipe file: {}", path.display()))?;
+    Ok(app_params)
+}
+
+/// AppParamsをYAML形式のレシピファイルとして書き出す。
+pub fn save_recipe<P: AsRef<Path>>(path: P, app_params: &AppParams) -> Result<()> {
+    let path = path.as_ref();
+    let file = File::create(path)
+        .with_context(|| format!("failed to create recipe file: {}", path.display()))?;
+    serde_yaml::to_writer(BufWriter::new(file), app_params)
+        .with_context(|| format!("failed to write recipe file: {}", path.display()))?;
+    Ok(())
+}
+
+/// 各フィルタ処理の値を検査する。エラーには何番目の処理かを含める。
+fn validate_recipe(app_params: &AppParams) -> Result<()> {
+    ensure!(
+        !app_params.filepath.is_empty(),
+        AppError::InvalidOption("filepath must contain at least one input.".to_string())
+    );
+    ensure!(
+        !app_params.processes.is_empty(),
+        AppError::InvalidOption("processes must contain at least one filter.".to_string())
+    );
+    app_params.encode.validate().context("encode")?;
+    for (idx, process) in app_params.processes.iter().enumerate() {
+        process
+            .validate()
+            .with_context(|| format!("processes[{}] ({})", idx, process))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use std::fmt::Display;
+
+/// 処理の失敗の種類。`anyhow::Error`にエラーそのものかcontextとして載せて返し、
+/// 呼び出し側は`downcast_ref`で種類を調べる。
+#[derive(Debug)]
+pub enum AppError {
+    /// 入力画像を読めない。
+    Decode(String),
+    /// 出力画像を書き出せない。
+    Encode(String),
+    /// 扱えない画像フォーマット。
+    UnsupportedFormat(String),
+    /// フィルタを適用する領域が不正。
+    InvalidRegion(String),
+    /// フィルタやエンコードのオプションが不正。
+    InvalidOption(String),
+    /// ImageMagickでの処理に失敗した。
+    Magick(String),
+    /// 複数のファイルを処理して、一部が失敗した。
+    Batch(String),
+}
+impl AppError {
+    /// `main`が返す終了コード。1はその他のエラー、2はコマンドライン引数の誤り (clap) に使う。
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Decode(_) => 3,
+            Self::Encode(_) => 4,
+            Self::UnsupportedFormat(_) => 5,
+            Self::InvalidRegion(_) => 6,
+            Self::InvalidOption(_) => 7,
+            Self::Magick(_) => 8,
+            Self::Batch(_) => 9,
+        }
+    }
+}
+impl Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Decode(message)
+            | Self::Encode(message)
+            | Self::UnsupportedFormat(message)
+            | Self::InvalidRegion(message)
+            | Self::InvalidOption(message)
+            | Self::Magick(message)
+            | Self::Batch(message) => write!(f, "{}", message),
+        }
+    }
+}
+impl std::error::Error for AppError {}
+
+/// エラーに対応する終了コードを返す。種類の分からないエラーは1になる。
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.downcast_ref::<AppError>()
+        .map_or(1, AppError::exit_code)
+}
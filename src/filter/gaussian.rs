@@ -1,56 +1,116 @@
 use std::fmt::Display;
+use std::ops::Bound;
 
-use image::{GenericImageView, ImageBuffer};
+use image::{ImageBuffer, Pixel, Rgba};
 use num_traits::Zero;
+use rayon::prelude::*;
+use serde::{de::Error as _, Deserialize as _, Deserializer};
+use serde_derive::{Deserialize, Serialize};
 
 use crate::{
-    arithmetic::TripleNums,
-    process::{FilterProcessor, FilterProcessorOptions},
+    arithmetic::{Subpixel, TripleNums},
+    process::{par_image_from_fn, FilterProcessor, FilterProcessorOptions, SourceView},
 };
 
-// fn gaussian<T: Float + FloatConst + Copy>(sigma: T, x: T, y: T) -> T {
-//     let half = T::from(0.5).unwrap();
-//     // 1 / \sqrt{2\pi}.
-//     let coeff = half * T::FRAC_2_SQRT_PI() * T::FRAC_1_SQRT_2();
-//     (-(x * x + y * y) * half / sigma / sigma).exp() * coeff / sigma
-// }
-const GAUSSIAN_COEFF: f64 = std::f64::consts::FRAC_1_PI * 0.5;
-fn gaussian_dist(sigma: f64, dist: u32) -> f64 {
-    let x = dist as f64;
-    (-(x * x) * 0.5 / sigma / sigma).exp() * GAUSSIAN_COEFF / sigma / sigma
+use super::{registry::RegistrableFilter, schema::OptionSpec};
+
+/// 中心からの距離ごとの1次元ガウス関数の値。和が1になるように正規化する。
+fn gaussian_kernel(sigma: f64, radius: u32) -> Vec<f64> {
+    let kernel = (0..=radius)
+        .map(|dist| {
+            let x = dist as f64;
+            (-(x * x) * 0.5 / sigma / sigma).exp()
+        })
+        .collect::<Vec<f64>>();
+    let sum = kernel[0] + 2.0 * kernel[1..].iter().sum::<f64>();
+    kernel.into_iter().map(|coeff| coeff / sum).collect()
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct GaussianFilterOption {
-    pub window_size: u32,
+    /// 窓の半径。省略するか`auto`にするとsigmaの3倍になる。
+    #[serde(
+        alias = "window",
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_window_size"
+    )]
+    pub window_size: Option<u32>,
     pub sigma: f64,
 }
+/// `window_size`は数のほかに`auto`も受け付ける。
+fn deserialize_window_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum WindowSize {
+        Size(i64),
+        Keyword(String),
+    }
+    let invalid = |value: &dyn Display| {
+        D::Error::custom(format!(
+            "invalid window_size: {} (expected a positive integer or auto)",
+            value
+        ))
+    };
+    match Option::<WindowSize>::deserialize(deserializer)? {
+        Some(WindowSize::Size(size)) => u32::try_from(size).map(Some).map_err(|_| invalid(&size)),
+        Some(WindowSize::Keyword(keyword)) if !keyword.eq_ignore_ascii_case("auto") => {
+            Err(invalid(&keyword))
+        }
+        _ => Ok(None),
+    }
+}
 impl GaussianFilterOption {
-    pub fn new(window_size: u32, sigma: f64) -> Self {
+    pub fn new(window_size: Option<u32>, sigma: f64) -> Self {
         Self { window_size, sigma }
     }
+    /// 実際に使う窓の半径。
+    pub fn radius(&self) -> u32 {
+        self.window_size
+            .unwrap_or_else(|| (3.0 * self.sigma).ceil() as u32)
+    }
 }
 impl Default for GaussianFilterOption {
     fn default() -> Self {
         Self {
-            window_size: 10,
+            window_size: None,
             sigma: 5.0,
         }
     }
 }
 impl Display for GaussianFilterOption {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "(window_size={}, sigma={})",
-            self.window_size, self.sigma
-        )
+        match self.window_size {
+            Some(window_size) => write!(f, "(window_size={}, sigma={})", window_size, self.sigma),
+            None => write!(
+                f,
+                "(window_size=auto ({}), sigma={})",
+                self.radius(),
+                self.sigma
+            ),
+        }
+    }
+}
+impl FilterProcessorOptions for GaussianFilterOption {
+    fn schema() -> Vec<OptionSpec> {
+        let default = Self::default();
+        vec![
+            OptionSpec::float(
+                "sigma",
+                "standard deviation in pixels",
+                (Bound::Excluded(0.0), Bound::Unbounded),
+            )
+            .with_default(default.sigma),
+            OptionSpec::integer("window_size", "blur radius in pixels", 1..).with_auto(),
+        ]
     }
 }
-impl FilterProcessorOptions for GaussianFilterOption {}
 
-/// ガウスぼかしフィルタ
-#[derive(Debug, Clone)]
+/// ガウスぼかしフィルタ。色はアルファを乗算した値でぼかすので、透明部分の色がにじまない。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct GaussianFilter {
     pub option: GaussianFilterOption,
 }
@@ -65,45 +125,65 @@ impl Display for GaussianFilter {
         write!(f, "Gaussian {}", self.option)
     }
 }
+impl RegistrableFilter for GaussianFilter {
+    const NAME: &'static str = "gaussian";
+    const DESCRIPTION: &'static str = "Gaussian blur";
+}
 impl FilterProcessor for GaussianFilter {
     type OptionsType = GaussianFilterOption;
-    fn process(
-        &self,
-        buf: &ImageBuffer<image::Rgb<u8>, Vec<u8>>,
-    ) -> ImageBuffer<image::Rgb<u8>, Vec<u8>> {
-        let GaussianFilterOption { window_size, sigma } = self.option;
-        let (buf_width, buf_height) = buf.dimensions();
-        let mut result_buf = buf.clone();
-        for j in 0..buf_height {
-            for i in 0..buf_width {
-                let window_edge_size = 2 * window_size + 1;
-                let view_left = i.saturating_sub(window_size);
-                let view_top = j.saturating_sub(window_size);
-                let view_width = buf_width.min(i + window_size + 1) - view_left;
-                let view_height = buf_height.min(j + window_size + 1) - view_top;
-                let sub_view = buf.view(view_left, view_top, view_width, view_height);
-                let mut coeff_sum = 0f64;
-                let mut color_sum = TripleNums::<f64>::zero();
-                // 距離ごとにガウス関数の値を計算しておく
-                let coeff_array = (0..(window_edge_size))
-                    .map(|dist| gaussian_dist(sigma, dist))
-                    .collect::<Vec<f64>>();
-                // 実際に使われた窓の重み付き平均を取るため都度計算している
-                for (x, y, color) in sub_view.pixels() {
-                    // (x, y)が(0, 0), (1, 0)...と取得される。
-                    // 実際の座標は(view_left + x, view_top + y)になる。
-                    let &coeff = coeff_array
-                        .get(((view_left + x).abs_diff(i) + (view_top + y).abs_diff(j)) as usize)
-                        .unwrap();
-                    coeff_sum += coeff;
-                    color_sum = color_sum + TripleNums(color.0).to_f64() * coeff;
-                }
-                result_buf.put_pixel(i, j, image::Rgb::from((color_sum / coeff_sum).to_u8().0));
+    fn process<S: Subpixel>(&self, src: &SourceView<S>) -> ImageBuffer<Rgba<S>, Vec<S>>
+    where
+        Rgba<S>: Pixel<Subpixel = S>,
+    {
+        let radius = self.option.radius();
+        let kernel = gaussian_kernel(self.option.sigma, radius);
+        let kernel = kernel.as_slice();
+        let (buf_width, buf_height) = src.dimensions();
+        let radius = radius as i64;
+        // 横方向にぼかす。縦方向のパスで使うので、矩形の上下に半径分はみ出した行も計算する。
+        // 色はアルファを掛けた値で持つ。窓が矩形や画像の外にはみ出しても、ビューが周囲のピクセルを返す
+        let horizontal = (-radius..buf_height as i64 + radius)
+            .into_par_iter()
+            .flat_map_iter(|j| {
+                (0..buf_width as i64).map(move |i| {
+                    let mut alpha_sum = 0f64;
+                    let mut color_sum = TripleNums::<f64>::zero();
+                    for dx in -radius..=radius {
+                        let coeff = kernel[dx.unsigned_abs() as usize];
+                        let [r, g, b, a] = src.get_pixel(i + dx, j).0;
+                        let alpha = a.to_f64().unwrap() * coeff;
+                        alpha_sum += alpha;
+                        color_sum = color_sum + TripleNums([r, g, b]).to_f64() * alpha;
+                    }
+                    (color_sum, alpha_sum)
+                })
+            })
+            .collect::<Vec<(TripleNums<f64>, f64)>>();
+        // 縦方向にぼかす
+        par_image_from_fn(buf_width, buf_height, |i, j| {
+            let (i, j) = (i as i64, j as i64);
+            let mut alpha_sum = 0f64;
+            let mut color_sum = TripleNums::<f64>::zero();
+            for dy in -radius..=radius {
+                let coeff = kernel[dy.unsigned_abs() as usize];
+                let (color, alpha) =
+                    horizontal[((j + radius + dy) * buf_width as i64 + i) as usize];
+                alpha_sum += alpha * coeff;
+                color_sum = color_sum + color * coeff;
             }
-        }
-        result_buf
+            let [r, g, b] = if alpha_sum > 0.0 {
+                (color_sum / alpha_sum).round::<S>().0
+            } else {
+                [S::zero(); 3]
+            };
+            let a = S::round_from_f64(alpha_sum);
+            Rgba([r, g, b, a])
+        })
     }
     fn get_option(&self) -> Self::OptionsType {
         self.option.clone()
     }
+    fn radius(&self) -> u32 {
+        self.option.radius()
+    }
 }
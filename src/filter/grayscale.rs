@@ -1,16 +1,23 @@
 use std::fmt::Display;
 
-use image::{DynamicImage, ImageBuffer};
+use image::{ImageBuffer, Pixel, Rgba};
+use serde_derive::{Deserialize, Serialize};
 
-use crate::process::{EmptyOption, FilterProcessor};
+use crate::{
+    arithmetic::Subpixel,
+    process::{par_image_from_fn, EmptyOption, FilterProcessor, SourceView},
+};
 
-/// グレイスケールにするフィルタ
-#[derive(Debug, Clone)]
-pub struct GrayscaleFilter;
+use super::registry::RegistrableFilter;
+
+/// グレイスケールにするフィルタ。アルファは変更しない。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrayscaleFilter {}
 
 impl GrayscaleFilter {
     pub fn new() -> Self {
-        Self
+        Self {}
     }
 }
 impl Display for GrayscaleFilter {
@@ -18,18 +25,27 @@ impl Display for GrayscaleFilter {
         write!(f, "Grayscale")
     }
 }
+impl RegistrableFilter for GrayscaleFilter {
+    const NAME: &'static str = "grayscale";
+    const DESCRIPTION: &'static str = "convert to grayscale";
+    const ALIASES: &'static [&'static str] = &["gray"];
+}
 impl FilterProcessor for GrayscaleFilter {
     type OptionsType = EmptyOption;
-    fn process(
-        &self,
-        buf: &ImageBuffer<image::Rgb<u8>, Vec<u8>>,
-    ) -> ImageBuffer<image::Rgb<u8>, Vec<u8>> {
-        let image = DynamicImage::from(buf.clone());
-        let gray = image.grayscale();
-        gray.into_rgb8()
-        // TODO:
+    fn process<S: Subpixel>(&self, src: &SourceView<S>) -> ImageBuffer<image::Rgba<S>, Vec<S>>
+    where
+        Rgba<S>: Pixel<Subpixel = S>,
+    {
+        let (width, height) = src.dimensions();
+        par_image_from_fn(width, height, |x, y| {
+            let [luma, alpha] = src.get_pixel(x as i64, y as i64).to_luma_alpha().0;
+            Rgba([luma, luma, luma, alpha])
+        })
     }
     fn get_option(&self) -> Self::OptionsType {
         EmptyOption
     }
+    fn radius(&self) -> u32 {
+        0
+    }
 }
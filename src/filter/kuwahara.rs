@@ -1,16 +1,20 @@
 use std::fmt::Display;
 
-use image::{GenericImage, GenericImageView, ImageBuffer, Rgb};
-use num_traits::Zero;
+use image::{ImageBuffer, Pixel, Rgba};
+use serde_derive::{Deserialize, Serialize};
 
 use crate::{
-    arithmetic::TripleNums,
-    process::{FilterProcessor, FilterProcessorOptions},
+    arithmetic::{Subpixel, TripleNums},
+    process::{par_fill_rows, FilterProcessor, FilterProcessorOptions, SourceView},
 };
 
-#[derive(Debug, Clone)]
+use super::{registry::RegistrableFilter, schema::OptionSpec};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct KuwaharaFilterOptions {
     /// Kuwahara filterの平均化する近傍窓サイズ。デフォルトは3。
+    #[serde(alias = "window")]
     pub window_size: u32,
 }
 impl KuwaharaFilterOptions {
@@ -28,9 +32,18 @@ impl Display for KuwaharaFilterOptions {
         write!(f, "(window_size={})", self.window_size)
     }
 }
-impl FilterProcessorOptions for KuwaharaFilterOptions {}
+impl FilterProcessorOptions for KuwaharaFilterOptions {
+    fn schema() -> Vec<OptionSpec> {
+        vec![
+            OptionSpec::integer("window_size", "size of each quadrant in pixels", 1..)
+                .with_default(Self::default().window_size),
+        ]
+    }
+}
 
-#[derive(Debug, Clone)]
+/// Kuwaharaフィルタ。分散と平均はアルファを乗算した色で計算し、選んだ領域の平均アルファを使う。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct KuwaharaFilter {
     pub option: KuwaharaFilterOptions,
 }
@@ -44,78 +57,136 @@ impl Display for KuwaharaFilter {
         write!(f, "Kuwahara {}", self.option)
     }
 }
+/// 累積和テーブルを一度に作る行数。大きな画像でもテーブルのメモリが増えすぎないようにする。
+const BAND_HEIGHT: i64 = 256;
+
+/// 矩形の和をO(1)で求めるための累積和テーブル (summed-area table)。
+/// 各ピクセルの値は(R, G, B, R²+G²+B², A)で、色はアルファを乗算した値。
+struct SummedAreaTable {
+    width: usize,
+    values: Vec<[f64; 5]>,
+}
+impl SummedAreaTable {
+    fn new(width: usize, height: usize, value: impl Fn(usize, usize) -> [f64; 5]) -> Self {
+        // 上端と左端に0の行と列を置き、境界の場合分けをなくす
+        let stride = width + 1;
+        let mut values = vec![[0f64; 5]; stride * (height + 1)];
+        for y in 0..height {
+            let mut row_sum = [0f64; 5];
+            for x in 0..width {
+                let pixel = value(x, y);
+                let above = values[y * stride + x + 1];
+                for k in 0..5 {
+                    row_sum[k] += pixel[k];
+                }
+                values[(y + 1) * stride + x + 1] = [0, 1, 2, 3, 4].map(|k| above[k] + row_sum[k]);
+            }
+        }
+        Self { width, values }
+    }
+    /// 左上(x0, y0)を含み右下(x1, y1)を含まない矩形の和。
+    fn sum(&self, (x0, y0): (usize, usize), (x1, y1): (usize, usize)) -> [f64; 5] {
+        let stride = self.width + 1;
+        let at = |x: usize, y: usize| self.values[y * stride + x];
+        let (a, b, c, d) = (at(x0, y0), at(x1, y0), at(x0, y1), at(x1, y1));
+        [0, 1, 2, 3, 4].map(|k| d[k] - b[k] - c[k] + a[k])
+    }
+}
+
+impl RegistrableFilter for KuwaharaFilter {
+    const NAME: &'static str = "kuwahara";
+    const DESCRIPTION: &'static str = "edge-preserving smoothing (painterly look)";
+}
 impl FilterProcessor for KuwaharaFilter {
     type OptionsType = KuwaharaFilterOptions;
-    fn process(&self, buf: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-        let window_size = self.option.window_size;
-        let (buf_width, buf_height) = buf.dimensions();
-        let mut buf = buf.clone();
-        let mut result_buf = buf.clone();
-        for i in 0..buf_width {
-            for j in 0..buf_height {
-                // 分散の和とRGB平均を4近傍ごとに保存する。 index: [x][y]
-                let mut var_sum_array = [[0f64; 2]; 2];
-                let mut mean_rgb_array = [[[0u8; 3]; 2]; 2];
-                // 4近傍の端にあたるピクセル番号を計算する（3点の直積で4近傍を表現できる）
-                let neighbour_edge_x = [
-                    (i + 1).saturating_sub(window_size),
-                    i,
-                    buf_width.min(i + window_size) - 1,
-                ];
-                let neighbour_edge_y = [
-                    (j + 1).saturating_sub(window_size),
-                    j,
-                    buf_height.min(j + window_size) - 1,
-                ];
+    fn process<S: Subpixel>(&self, src: &SourceView<S>) -> ImageBuffer<Rgba<S>, Vec<S>>
+    where
+        Rgba<S>: Pixel<Subpixel = S>,
+    {
+        let window_size = self.option.window_size as i64;
+        let (buf_width, buf_height) = src.dimensions();
+        let mut result_buf = ImageBuffer::new(buf_width, buf_height);
+        let pix_num_f = (window_size * window_size) as f64;
+        // 近傍は中心から窓サイズ-1だけはみ出すので、テーブルもその分広く作る。
+        // 近傍が矩形や画像の外にはみ出しても、ビューが周囲のピクセルを返す
+        let margin = window_size - 1;
+        let table_width = (buf_width as i64 + 2 * margin) as usize;
+        for band_top in (0..buf_height as i64).step_by(BAND_HEIGHT as usize) {
+            let band_bottom = (band_top + BAND_HEIGHT).min(buf_height as i64);
+            let table_height = (band_bottom - band_top + 2 * margin) as usize;
+            // 色はアルファを乗算した値で扱う
+            let table = SummedAreaTable::new(table_width, table_height, |x, y| {
+                let color = src.get_pixel(x as i64 - margin, band_top - margin + y as i64);
+                let [r, g, b, a] = color.0;
+                let a = a.to_f64().unwrap();
+                let [r, g, b] = (TripleNums([r, g, b]).to_f64() * (a / S::max_f64())).0;
+                [r, g, b, r * r + g * g + b * b, a]
+            });
+            par_fill_rows(
+                &mut result_buf,
+                band_top as u32..band_bottom as u32,
+                |i, j| {
+                    let (i, j) = (i as i64, j as i64);
+                    // 分散の和とRGBA平均を4近傍ごとに保存する。 index: [x][y]
+                    let mut var_sum_array = [[0f64; 2]; 2];
+                    let mut mean_rgba_array = [[[S::zero(); 4]; 2]; 2];
+                    // 4近傍の端にあたるピクセル番号をテーブル上の座標で計算する（3点の直積で4近傍を表現できる）
+                    let neighbour_edge_x = [i, i + margin, i + 2 * margin].map(|x| x as usize);
+                    let neighbour_edge_y = [
+                        j - band_top,
+                        j - band_top + margin,
+                        j - band_top + 2 * margin,
+                    ]
+                    .map(|y| y as usize);
 
-                for (block_x, block_y) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
-                    // 各近傍について演算
-                    let block_min_x = neighbour_edge_x[block_x];
-                    let block_min_y = neighbour_edge_y[block_y];
-                    let block_max_x = neighbour_edge_x[block_x + 1];
-                    let block_max_y = neighbour_edge_y[block_y + 1];
-                    let sub_width = block_max_x + 1 - block_min_x;
-                    let sub_height = block_max_y + 1 - block_min_y;
+                    for (block_x, block_y) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                        // 各近傍について演算
+                        let block_min = (neighbour_edge_x[block_x], neighbour_edge_y[block_y]);
+                        let block_max = (
+                            neighbour_edge_x[block_x + 1] + 1,
+                            neighbour_edge_y[block_y + 1] + 1,
+                        );
+                        let [r, g, b, double_sum, alpha_sum] = table.sum(block_min, block_max);
+                        let sum = TripleNums([r, g, b]);
+                        // 分散の和 Σ(E[c²] - E[c]²) を、整数値の画素で丸め誤差が出ないように通分して求める
+                        let variance_sum = (double_sum * pix_num_f
+                            - (sum * sum).iter().sum::<f64>())
+                            / pix_num_f
+                            / pix_num_f;
+                        var_sum_array[block_x][block_y] = variance_sum;
+                        // RGBA平均は後で選べるように保存しておく
+                        let mean_alpha = alpha_sum / pix_num_f;
+                        let [r, g, b] = if alpha_sum > 0.0 {
+                            (sum / mean_alpha * S::max_f64() / pix_num_f).round::<S>().0
+                        } else {
+                            [S::zero(); 3]
+                        };
+                        let mean_alpha = S::round_from_f64(mean_alpha);
+                        mean_rgba_array[block_x][block_y] = [r, g, b, mean_alpha];
+                    }
 
-                    let sub_image = buf.sub_image(block_min_x, block_min_y, sub_width, sub_height);
-                    let (sum, double_sum) = sub_image
-                        .pixels()
-                        .map(|(_, _, color)| TripleNums(color.0).to_f64())
-                        .fold(
-                            (TripleNums::<f64>::zero(), TripleNums::<f64>::zero()),
-                            |(prev_single, prev_double), curr| {
-                                (prev_single + curr, prev_double + curr * curr)
+                    // 各ブロックの値を比較して最も小さい領域の平均RGBをとる。
+                    let (min_block_index_x, min_block_index_y, _) =
+                        [(0, 0), (0, 1), (1, 0), (1, 1)].into_iter().fold(
+                            (0, 0, f64::MAX),
+                            |(prev_x, prev_y, prev_val), (block_x, block_y)| {
+                                if var_sum_array[block_x][block_y] < prev_val {
+                                    (block_x, block_y, var_sum_array[block_x][block_y])
+                                } else {
+                                    (prev_x, prev_y, prev_val)
+                                }
                             },
                         );
-                    let pix_num_f = sub_image.pixels().count() as f64;
-                    let variances = double_sum / pix_num_f - sum * sum / pix_num_f / pix_num_f;
-                    var_sum_array[block_x][block_y] = variances.iter().sum::<f64>();
-                    // RGB平均は後で選べるように保存しておく
-                    mean_rgb_array[block_x][block_y] = (sum / pix_num_f).to_u8().0;
-                }
-
-                // 各ブロックの値を比較して最も小さい領域の平均RGBをとる。
-                let (min_block_index_x, min_block_index_y, _) =
-                    [(0, 0), (0, 1), (1, 0), (1, 1)].into_iter().fold(
-                        (0, 0, f64::MAX),
-                        |(prev_x, prev_y, prev_val), (block_x, block_y)| {
-                            if var_sum_array[block_x][block_y] < prev_val {
-                                (block_x, block_y, var_sum_array[block_x][block_y])
-                            } else {
-                                (prev_x, prev_y, prev_val)
-                            }
-                        },
-                    );
-                result_buf.put_pixel(
-                    i,
-                    j,
-                    Rgb::<u8>(mean_rgb_array[min_block_index_x][min_block_index_y]),
-                );
-            }
+                    Rgba::<S>(mean_rgba_array[min_block_index_x][min_block_index_y])
+                },
+            );
         }
         result_buf
     }
     fn get_option(&self) -> Self::OptionsType {
         self.option.clone()
     }
+    fn radius(&self) -> u32 {
+        self.option.window_size - 1
+    }
 }
@@ -1,16 +1,24 @@
 use std::fmt::Display;
+use std::sync::Arc;
 
-use crate::process::{EmptyOption, FilterProcessor};
+use anyhow::{anyhow, Result};
+use image::{Pixel, Rgba};
+use serde::{de::Error as _, ser::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use serde_yaml::{Mapping, Value};
 
-use self::{
-    gaussian::GaussianFilter, grayscale::GrayscaleFilter, kuwahara::KuwaharaFilter,
-    mosaic::MosaicFilter, truncate_color::TruncateColorFilter,
+use crate::{
+    arithmetic::Subpixel,
+    process::{DynFilterProcessor, EmptyOption, FilterProcessor, SourceView},
 };
 
+use self::registry::RegistrableFilter;
+
 pub mod gaussian;
 pub mod grayscale;
 pub mod kuwahara;
 pub mod mosaic;
+pub mod registry;
+pub mod schema;
 pub mod truncate_color;
 
 pub mod prelude {
@@ -23,65 +31,95 @@ pub mod prelude {
     };
 }
 
-#[derive(Clone, Copy, Debug)]
-pub enum AppFilterType {
-    Gaussian,
-    GrayScale,
-    Kuwahara,
-    Mosaic,
-    Truncate,
+/// レジストリに登録されたフィルタのいずれか。
+/// レシピファイルでは`type`キーでフィルタの名前を指定し、同じ階層にオプションを並べる。
+#[derive(Clone)]
+pub struct AppFilter {
+    name: &'static str,
+    filter: Arc<dyn DynFilterProcessor>,
 }
-impl std::fmt::Display for AppFilterType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+impl AppFilter {
+    pub fn new<F: RegistrableFilter>(filter: F) -> Self {
+        Self {
+            name: F::NAME,
+            filter: Arc::new(filter),
+        }
     }
-}
-impl AppFilterType {
-    pub fn create_vec() -> Vec<Self> {
-        vec![
-            Self::Gaussian,
-            Self::GrayScale,
-            Self::Kuwahara,
-            Self::Mosaic,
-            Self::Truncate,
-        ]
+    /// 登録されたフィルタを名前 (または別名) とオプションから組み立てる。
+    /// 指定されなかったオプションはデフォルト値になる。
+    pub fn from_options(name: &str, options: Value) -> Result<Self> {
+        registry::lookup(name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown filter: {} (expected {})",
+                    name,
+                    registry::names().join(", ")
+                )
+            })?
+            .build(options)
+    }
+    /// レジストリに登録された名前。
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    /// フィルタのオプションが有効か検査する。
+    pub fn validate(&self) -> Result<()> {
+        self.filter.validate()
     }
 }
-
-#[derive(Clone, Debug)]
-pub enum AppFilter {
-    Gaussian(GaussianFilter),
-    GrayScale(GrayscaleFilter),
-    Kuwahara(KuwaharaFilter),
-    Mosaic(MosaicFilter),
-    Truncate(TruncateColorFilter),
+impl std::fmt::Debug for AppFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&self.filter, f)
+    }
 }
 impl Display for AppFilter {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Gaussian(filter) => filter.fmt(f),
-            Self::GrayScale(filter) => filter.fmt(f),
-            Self::Kuwahara(filter) => filter.fmt(f),
-            Self::Mosaic(filter) => filter.fmt(f),
-            Self::Truncate(filter) => filter.fmt(f),
-        }
+        Display::fmt(&self.filter, f)
+    }
+}
+impl Serialize for AppFilter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let options = match self.filter.options().map_err(S::Error::custom)? {
+            Value::Mapping(options) => options,
+            Value::Null => Mapping::new(),
+            _ => return Err(S::Error::custom("filter options must be a mapping.")),
+        };
+        let mut map = Mapping::new();
+        map.insert("type".into(), self.name.into());
+        map.extend(options);
+        map.serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for AppFilter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut map = Mapping::deserialize(deserializer)?;
+        let name = match map.remove("type") {
+            Some(Value::String(name)) => name,
+            Some(_) => return Err(D::Error::custom("filter `type` must be a string.")),
+            None => return Err(D::Error::missing_field("type")),
+        };
+        AppFilter::from_options(&name, Value::Mapping(map))
+            .map_err(|err| D::Error::custom(format!("{:#}", err)))
     }
 }
 impl FilterProcessor for AppFilter {
     type OptionsType = EmptyOption;
-    fn process(
-        &self,
-        buf: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>,
-    ) -> image::ImageBuffer<image::Rgb<u8>, Vec<u8>> {
-        match self {
-            Self::Gaussian(filter) => filter.process(buf),
-            Self::GrayScale(filter) => filter.process(buf),
-            Self::Kuwahara(filter) => filter.process(buf),
-            Self::Mosaic(filter) => filter.process(buf),
-            Self::Truncate(filter) => filter.process(buf),
-        }
+    fn process<S: Subpixel>(&self, src: &SourceView<S>) -> image::ImageBuffer<Rgba<S>, Vec<S>>
+    where
+        Rgba<S>: Pixel<Subpixel = S>,
+    {
+        S::process_dyn(self.filter.as_ref(), src)
     }
     fn get_option(&self) -> Self::OptionsType {
         EmptyOption
     }
+    fn radius(&self) -> u32 {
+        self.filter.radius()
+    }
+    fn tile_alignment(&self) -> u32 {
+        self.filter.tile_alignment()
+    }
+    fn validate(&self) -> Result<()> {
+        self.filter.validate()
+    }
 }
@@ -1,15 +1,22 @@
 use std::fmt::Display;
 
-use image::ImageBuffer;
+use image::{ImageBuffer, Pixel, Rgba};
+use serde_derive::{Deserialize, Serialize};
 
-use crate::process::{FilterProcessor, FilterProcessorOptions};
+use crate::{
+    arithmetic::Subpixel,
+    process::{par_image_from_fn, FilterProcessor, FilterProcessorOptions, SourceView},
+};
 
-#[derive(Debug, Clone)]
+use super::{registry::RegistrableFilter, schema::OptionSpec};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct MosaicFilterOption {
-    pub size: usize,
+    pub size: u32,
 }
 impl MosaicFilterOption {
-    pub fn new(size: usize) -> Self {
+    pub fn new(size: u32) -> Self {
         Self { size }
     }
 }
@@ -23,9 +30,15 @@ impl Display for MosaicFilterOption {
         write!(f, "(size={})", self.size)
     }
 }
-impl FilterProcessorOptions for MosaicFilterOption {}
-/// モザイクフィルタ
-#[derive(Debug, Clone)]
+impl FilterProcessorOptions for MosaicFilterOption {
+    fn schema() -> Vec<OptionSpec> {
+        vec![OptionSpec::integer("size", "block size in pixels", 1..)
+            .with_default(Self::default().size)]
+    }
+}
+/// モザイクフィルタ。各ブロックの左上のピクセルをアルファごと複製する。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct MosaicFilter {
     pub option: MosaicFilterOption,
 }
@@ -40,28 +53,31 @@ impl Display for MosaicFilter {
         write!(f, "Mosaic {}", self.option)
     }
 }
+impl RegistrableFilter for MosaicFilter {
+    const NAME: &'static str = "mosaic";
+    const DESCRIPTION: &'static str = "pixelate in square blocks";
+}
 impl FilterProcessor for MosaicFilter {
     type OptionsType = MosaicFilterOption;
-    fn process(
-        &self,
-        buf: &ImageBuffer<image::Rgb<u8>, Vec<u8>>,
-    ) -> ImageBuffer<image::Rgb<u8>, Vec<u8>> {
+    fn process<S: Subpixel>(&self, src: &SourceView<S>) -> ImageBuffer<Rgba<S>, Vec<S>>
+    where
+        Rgba<S>: Pixel<Subpixel = S>,
+    {
         let MosaicFilterOption { size } = self.option;
-        let (buf_width, buf_height) = buf.dimensions();
-        let mut result_buf = buf.clone();
-        for block_y in (0..buf_height).collect::<Vec<u32>>().chunks(size) {
-            for block_x in (0..buf_width).collect::<Vec<u32>>().chunks(size) {
-                let &block_pix = buf.get_pixel(block_x[0], block_y[0]);
-                for &y in block_y {
-                    for &x in block_x {
-                        result_buf.put_pixel(x, y, block_pix);
-                    }
-                }
-            }
-        }
-        result_buf
+        let (buf_width, buf_height) = src.dimensions();
+        // 各ピクセルは属するブロックの左上のピクセルになる
+        par_image_from_fn(buf_width, buf_height, |x, y| {
+            src.get_pixel((x - x % size) as i64, (y - y % size) as i64)
+        })
     }
     fn get_option(&self) -> Self::OptionsType {
         self.option.clone()
     }
+    fn radius(&self) -> u32 {
+        0
+    }
+    /// ブロックは矩形の左上から数えるので、タイルをブロックの大きさの倍数にしてブロックが分かれないようにする。
+    fn tile_alignment(&self) -> u32 {
+        self.option.size
+    }
 }
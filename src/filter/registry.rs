@@ -0,0 +1,161 @@
+use std::sync::{LazyLock, PoisonError, RwLock};
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use crate::process::{FilterProcessor, FilterProcessorOptions};
+
+use super::schema::OptionSpec;
+
+use super::{
+    gaussian::GaussianFilter, grayscale::GrayscaleFilter, kuwahara::KuwaharaFilter,
+    mosaic::MosaicFilter, truncate_color::TruncateColorFilter, AppFilter,
+};
+
+/// レジストリに登録できるフィルタ。オプションはserdeでYAMLの値と相互に変換し、
+/// 空のマッピングから組み立てたものをデフォルトとする。
+pub trait RegistrableFilter:
+    FilterProcessor + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    /// レシピの`type`や`--filter`に書く名前。
+    const NAME: &'static str;
+    /// 一覧に表示する説明。
+    const DESCRIPTION: &'static str;
+    /// `--filter`やレシピで名前の代わりに使える別名。
+    const ALIASES: &'static [&'static str] = &[];
+}
+
+/// 登録されたフィルタの名前、説明、オプションと、オプションからフィルタを作る関数。
+#[derive(Clone)]
+pub struct FilterEntry {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub description: &'static str,
+    pub options: Vec<OptionSpec>,
+    build: fn(Value) -> Result<AppFilter>,
+}
+impl FilterEntry {
+    pub fn of<F: RegistrableFilter>() -> Self {
+        Self {
+            name: F::NAME,
+            aliases: F::ALIASES,
+            description: F::DESCRIPTION,
+            options: F::OptionsType::schema(),
+            build: |options| Ok(AppFilter::new(serde_yaml::from_value::<F>(options)?)),
+        }
+    }
+    /// 名前か別名が一致するか。大文字と小文字は区別しない。
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(name))
+    }
+    /// オプションのマッピングからフィルタを作る。指定されなかったオプションはデフォルト値になる。
+    pub fn build(&self, options: Value) -> Result<AppFilter> {
+        let options = match options {
+            Value::Null => Value::Mapping(Mapping::new()),
+            options => options,
+        };
+        (self.build)(options).map_err(|err| anyhow!("invalid options for {}: {:#}", self.name, err))
+    }
+    /// オプションをすべてデフォルト値にしたフィルタ。
+    pub fn build_default(&self) -> Result<AppFilter> {
+        self.build(Value::Null)
+    }
+}
+impl std::fmt::Display for FilterEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} - {}", self.name, self.description)
+    }
+}
+
+static REGISTRY: LazyLock<RwLock<Vec<FilterEntry>>> = LazyLock::new(|| {
+    RwLock::new(vec![
+        FilterEntry::of::<GaussianFilter>(),
+        FilterEntry::of::<GrayscaleFilter>(),
+        FilterEntry::of::<KuwaharaFilter>(),
+        FilterEntry::of::<MosaicFilter>(),
+        FilterEntry::of::<TruncateColorFilter>(),
+    ])
+});
+
+/// フィルタを登録する。同じ名前のフィルタがすでにあれば置き換える。
+///
+/// ```
+/// use image::{ImageBuffer, Pixel, Rgba};
+/// use my_img_utilities::arithmetic::Subpixel;
+/// use my_img_utilities::filter::{registry::{self, RegistrableFilter}, AppFilter};
+/// use my_img_utilities::process::{par_image_from_fn, EmptyOption, FilterProcessor, SourceView};
+///
+/// /// 色を反転するフィルタ。
+/// #[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+/// struct Invert {}
+/// impl std::fmt::Display for Invert {
+///     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+///         write!(f, "Invert")
+///     }
+/// }
+/// impl FilterProcessor for Invert {
+///     type OptionsType = EmptyOption;
+///     fn process<S: Subpixel>(&self, src: &SourceView<S>) -> ImageBuffer<Rgba<S>, Vec<S>>
+///     where
+///         Rgba<S>: Pixel<Subpixel = S>,
+///     {
+///         let (width, height) = src.dimensions();
+///         par_image_from_fn(width, height, |x, y| {
+///             let mut pixel = src.get_pixel(x as i64, y as i64);
+///             pixel.apply_without_alpha(|v| S::DEFAULT_MAX_VALUE - v);
+///             pixel
+///         })
+///     }
+///     fn get_option(&self) -> Self::OptionsType {
+///         EmptyOption
+///     }
+///     fn radius(&self) -> u32 {
+///         0
+///     }
+/// }
+/// impl RegistrableFilter for Invert {
+///     const NAME: &'static str = "invert";
+///     const DESCRIPTION: &'static str = "invert colors";
+/// }
+///
+/// registry::register::<Invert>();
+/// let filter = AppFilter::from_options("invert", serde_yaml::Value::Null).unwrap();
+/// assert_eq!(filter.to_string(), "Invert");
+/// ```
+pub fn register<F: RegistrableFilter>() {
+    let entry = FilterEntry::of::<F>();
+    let mut registry = REGISTRY.write().unwrap_or_else(PoisonError::into_inner);
+    match registry.iter_mut().find(|e| e.name == entry.name) {
+        Some(existing) => *existing = entry,
+        None => registry.push(entry),
+    }
+}
+
+/// 名前か別名でフィルタを探す。
+pub fn lookup(name: &str) -> Option<FilterEntry> {
+    let registry = REGISTRY.read().unwrap_or_else(PoisonError::into_inner);
+    registry.iter().find(|entry| entry.matches(name)).cloned()
+}
+
+/// 登録されたフィルタを登録順に返す。
+pub fn entries() -> Vec<FilterEntry> {
+    REGISTRY
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// 登録されたフィルタの名前を登録順に返す。
+pub fn names() -> Vec<&'static str> {
+    entries().iter().map(|entry| entry.name).collect()
+}
+
+/// `key=value`の値を文字列からYAMLのスカラーとして読む。`5`は数、`auto`や`r`は文字列になる。
+pub fn parse_option_value(value: &str) -> Value {
+    serde_yaml::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
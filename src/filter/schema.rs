@@ -0,0 +1,164 @@
+use std::fmt::Display;
+use std::ops::{Bound, RangeBounds};
+
+use anyhow::{bail, Result};
+use serde_yaml::Value;
+
+use super::registry::parse_option_value;
+
+/// オプションの値の型と範囲。
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptionKind {
+    Integer {
+        min: Bound<i64>,
+        max: Bound<i64>,
+    },
+    Float {
+        min: Bound<f64>,
+        max: Bound<f64>,
+    },
+    /// 並べた文字列のいずれか。
+    Choice(&'static [&'static str]),
+}
+impl OptionKind {
+    /// 値が型と範囲に合っているか。
+    fn accepts(&self, value: &Value) -> bool {
+        match self {
+            Self::Integer { min, max } => value.as_i64().is_some_and(|v| in_bounds(&v, min, max)),
+            Self::Float { min, max } => value
+                .as_f64()
+                .is_some_and(|v| v.is_finite() && in_bounds(&v, min, max)),
+            Self::Choice(choices) => value.as_str().is_some_and(|v| choices.contains(&v)),
+        }
+    }
+}
+fn in_bounds<T: PartialOrd + Copy>(value: &T, min: &Bound<T>, max: &Bound<T>) -> bool {
+    (*min, *max).contains(value)
+}
+/// `integer >= 1`、`number > 0`、`one of r, g, b`のように表示する。
+impl Display for OptionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn bounds<T: Display>(
+            f: &mut std::fmt::Formatter<'_>,
+            min: &Bound<T>,
+            max: &Bound<T>,
+        ) -> std::fmt::Result {
+            match min {
+                Bound::Included(v) => write!(f, " >= {}", v)?,
+                Bound::Excluded(v) => write!(f, " > {}", v)?,
+                Bound::Unbounded => {}
+            }
+            if !matches!(min, Bound::Unbounded) && !matches!(max, Bound::Unbounded) {
+                write!(f, " and")?;
+            }
+            match max {
+                Bound::Included(v) => write!(f, " <= {}", v),
+                Bound::Excluded(v) => write!(f, " < {}", v),
+                Bound::Unbounded => Ok(()),
+            }
+        }
+        match self {
+            Self::Integer { min, max } => {
+                write!(f, "integer")?;
+                bounds(f, min, max)
+            }
+            Self::Float { min, max } => {
+                write!(f, "number")?;
+                bounds(f, min, max)
+            }
+            Self::Choice(choices) => write!(f, "one of {}", choices.join(", ")),
+        }
+    }
+}
+
+/// フィルタのオプション1つの説明。プロンプト、検査、ヘルプはこれから作る。
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionSpec {
+    /// レシピや`--filter`に書くキー。
+    pub name: &'static str,
+    /// 何を指定するオプションか。
+    pub help: &'static str,
+    pub kind: OptionKind,
+    /// デフォルト値の表示。
+    pub default: Option<String>,
+    /// `auto`と書けるか。`auto`は省略したのと同じになる。
+    pub auto: bool,
+}
+impl OptionSpec {
+    fn new(name: &'static str, help: &'static str, kind: OptionKind) -> Self {
+        Self {
+            name,
+            help,
+            kind,
+            default: None,
+            auto: false,
+        }
+    }
+    pub fn integer(name: &'static str, help: &'static str, range: impl RangeBounds<i64>) -> Self {
+        let kind = OptionKind::Integer {
+            min: range.start_bound().cloned(),
+            max: range.end_bound().cloned(),
+        };
+        Self::new(name, help, kind)
+    }
+    pub fn float(name: &'static str, help: &'static str, range: impl RangeBounds<f64>) -> Self {
+        let kind = OptionKind::Float {
+            min: range.start_bound().cloned(),
+            max: range.end_bound().cloned(),
+        };
+        Self::new(name, help, kind)
+    }
+    pub fn choice(
+        name: &'static str,
+        help: &'static str,
+        choices: &'static [&'static str],
+    ) -> Self {
+        Self::new(name, help, OptionKind::Choice(choices))
+    }
+    pub fn with_default(mut self, default: impl Display) -> Self {
+        self.default = Some(default.to_string());
+        self
+    }
+    pub fn with_auto(mut self) -> Self {
+        self.auto = true;
+        self
+    }
+    /// 値が型と範囲に合っているか検査する。
+    pub fn check(&self, value: &Value) -> Result<()> {
+        if !self.kind.accepts(value) {
+            let got = serde_yaml::to_string(value).unwrap_or_default();
+            let auto = if self.auto { " or auto" } else { "" };
+            bail!(
+                "{} must be {}{} (got {}).",
+                self.name,
+                self.kind,
+                auto,
+                got.trim_end()
+            );
+        }
+        Ok(())
+    }
+    /// 入力された文字列を値として読み、検査する。空欄と`auto`は`None`になる。
+    pub fn parse(&self, input: &str) -> Result<Option<Value>> {
+        let input = input.trim();
+        if input.is_empty() || (self.auto && input.eq_ignore_ascii_case("auto")) {
+            return Ok(None);
+        }
+        let value = parse_option_value(input);
+        self.check(&value)?;
+        Ok(Some(value))
+    }
+    /// 型、範囲、デフォルト値をまとめた説明。例: `integer >= 1; default 50`
+    pub fn describe(&self) -> String {
+        let mut description = self.kind.to_string();
+        if self.auto {
+            description.push_str(" or auto");
+        }
+        match &self.default {
+            Some(default) => description.push_str(&format!("; default {}", default)),
+            None if self.auto => description.push_str("; default auto"),
+            None => {}
+        }
+        description
+    }
+}
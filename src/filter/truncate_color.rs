@@ -1,13 +1,22 @@
 use std::fmt::Display;
 
-use image::ImageBuffer;
+use image::{ImageBuffer, Pixel, Rgba};
+use serde_derive::{Deserialize, Serialize};
 
-use crate::process::{FilterProcessor, FilterProcessorOptions};
+use crate::{
+    arithmetic::Subpixel,
+    process::{par_image_from_fn, FilterProcessor, FilterProcessorOptions, SourceView},
+};
 
-#[derive(Debug, Clone, Copy)]
+use super::{registry::RegistrableFilter, schema::OptionSpec};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum TruncateComponent {
+    #[serde(rename = "r", alias = "red")]
     R,
+    #[serde(rename = "g", alias = "green")]
     G,
+    #[serde(rename = "b", alias = "blue")]
     B,
 }
 impl TruncateComponent {
@@ -28,7 +37,8 @@ impl Display for TruncateComponent {
         )
     }
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct TruncateColorFilterOption {
     pub component: TruncateComponent,
 }
@@ -49,9 +59,19 @@ impl Default for TruncateColorFilterOption {
         }
     }
 }
-impl FilterProcessorOptions for TruncateColorFilterOption {}
-/// RGBのいずれかを0にするフィルタ。
-#[derive(Debug, Clone)]
+impl FilterProcessorOptions for TruncateColorFilterOption {
+    fn schema() -> Vec<OptionSpec> {
+        vec![OptionSpec::choice(
+            "component",
+            "RGB component to set to zero",
+            &["r", "g", "b"],
+        )
+        .with_default("r")]
+    }
+}
+/// RGBのいずれかを0にするフィルタ。アルファは変更しない。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct TruncateColorFilter {
     pub option: TruncateColorFilterOption,
 }
@@ -66,29 +86,32 @@ impl Display for TruncateColorFilter {
         write!(f, "TruncateColor {}", self.option)
     }
 }
+impl RegistrableFilter for TruncateColorFilter {
+    const NAME: &'static str = "truncate";
+    const DESCRIPTION: &'static str = "set one RGB component to zero";
+}
 impl FilterProcessor for TruncateColorFilter {
     type OptionsType = TruncateColorFilterOption;
-    fn process(
-        &self,
-        buf: &ImageBuffer<image::Rgb<u8>, Vec<u8>>,
-    ) -> ImageBuffer<image::Rgb<u8>, Vec<u8>> {
-        let mut buf = buf.to_owned();
-        let width = buf.width();
-        let height = buf.height();
-        for x in 0..width {
-            for y in 0..height {
-                let pixel = buf.get_pixel_mut(x, y);
-                let index = match self.option.component {
-                    TruncateComponent::R => 0,
-                    TruncateComponent::G => 1,
-                    TruncateComponent::B => 2,
-                };
-                pixel.0[index] = 0;
-            }
-        }
-        buf
+    fn process<S: Subpixel>(&self, src: &SourceView<S>) -> ImageBuffer<Rgba<S>, Vec<S>>
+    where
+        Rgba<S>: Pixel<Subpixel = S>,
+    {
+        let index = match self.option.component {
+            TruncateComponent::R => 0,
+            TruncateComponent::G => 1,
+            TruncateComponent::B => 2,
+        };
+        let (width, height) = src.dimensions();
+        par_image_from_fn(width, height, |x, y| {
+            let mut pixel = src.get_pixel(x as i64, y as i64);
+            pixel.0[index] = S::zero();
+            pixel
+        })
     }
     fn get_option(&self) -> Self::OptionsType {
         self.option.clone()
     }
+    fn radius(&self) -> u32 {
+        0
+    }
 }
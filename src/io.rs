@@ -1,18 +1,25 @@
-use anyhow::{ensure, Result};
-use image::{ImageBuffer, ImageResult, Rgb};
-use img_parts::jpeg::Jpeg;
-use img_parts::{Bytes, ImageICC};
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageError, ImageFormat};
+use img_parts::{Bytes, DynImage, ImageICC};
 use std::fs::File;
-use std::io::{self, BufReader, Read, Write};
-use std::path::{Path, PathBuf};
+use std::io::{self, BufReader, Cursor, Read, Write};
+use std::path::Path;
+use tiff::decoder::{ifd::Value as TiffValue, Decoder as TiffDecoder};
+use tiff::tags::Tag;
 
-use crate::my_magick::convert_to_jpeg_binary;
+use crate::error::AppError;
+use crate::metadata::{exif_orientation, read_metadata, Metadata};
+#[cfg(feature = "magick")]
+use crate::my_magick::decode_heic;
+use crate::process::PixelBuffer;
 
 pub fn read_binary<P>(path: P) -> Result<Vec<u8>>
 where
     P: AsRef<Path>,
 {
-    let mut file = File::open(path)?;
+    let path = path.as_ref();
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
     let mut buf = Vec::<u8>::new();
     file.read_to_end(&mut buf)?;
     Ok(buf)
@@ -22,7 +29,9 @@ pub fn write_binary<P>(path: P, data: &[u8]) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    let mut file = File::create(path)?;
+    let path = path.as_ref();
+    let mut file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
     let buf = BufReader::new(data)
         .bytes()
         .collect::<io::Result<Vec<u8>>>()?;
@@ -31,52 +40,121 @@ where
     Ok(())
 }
 
-pub fn change_extention(filepath: &PathBuf, to_ext: &str) -> Result<PathBuf> {
-    let mut path = std::fs::canonicalize(filepath).unwrap();
-
-    ensure!(path.is_file(), "the path is not a file.");
-    path.set_extension(to_ext);
-    Ok(path)
-}
+/// TIFFでICCプロファイルを格納するタグ番号。
+pub(crate) const TIFF_TAG_ICC_PROFILE: u16 = 34675;
 
 pub struct ImageData {
-    pub buffer: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    pub buffer: PixelBuffer,
     pub icc: Option<Bytes>,
+    pub metadata: Metadata,
 }
 impl ImageData {
-    pub fn new(buffer: ImageBuffer<Rgb<u8>, Vec<u8>>, icc: Option<Bytes>) -> Self {
-        Self { buffer, icc }
+    pub fn new(buffer: PixelBuffer, icc: Option<Bytes>, metadata: Metadata) -> Self {
+        Self {
+            buffer,
+            icc,
+            metadata,
+        }
     }
 }
 
-/// 受け取ったパスのファイルを読んで画像データとして返す。
-/// HEIC形式はimageクレートで読めないため、その場合はMagickが必要となる。
-/// TODO: featureとして分離すれば依存するmagickがなくてもコンパイルできそう
-pub fn read_image<P: AsRef<Path>>(path: P) -> ImageResult<ImageData>
-where
-    PathBuf: std::convert::From<P>,
-{
-    let pathbuf = PathBuf::from(path);
-    let ext = pathbuf.extension().unwrap_or_else(|| {
-        println!("error: cannot get extension (as format).");
-        std::process::exit(1);
-    });
-    if ext.to_ascii_lowercase() == "heic" {
-        // heicフォーマットはjpegに変換してからImageBufferにデコードする。
-        let file_buf = read_binary(pathbuf).unwrap();
-        let jpeg_binary = convert_to_jpeg_binary(&file_buf).unwrap();
-        let icc = Jpeg::from_bytes(jpeg_binary.clone().into())
-            .unwrap()
-            .icc_profile();
-        let buffer = image::load_from_memory_with_format(&jpeg_binary, image::ImageFormat::Jpeg)?
-            .into_rgb8();
-        Ok(ImageData::new(buffer, icc))
-    } else {
-        let dyn_image = image::open(pathbuf)?;
-        let icc = Jpeg::from_bytes(dyn_image.as_bytes().to_owned().into())
-            .unwrap()
-            .icc_profile();
-        let buffer = dyn_image.into_rgb8();
-        Ok(ImageData::new(buffer, icc))
+/// TIFFのタグからバイト列を読む。BYTE型とUNDEFINED型のどちらで書かれていても読めるようにする。
+pub(crate) fn read_tiff_bytes_tag(buf: &[u8], tag: u16) -> Option<Bytes> {
+    let mut decoder = TiffDecoder::new(Cursor::new(buf)).ok()?;
+    let to_u8 = |value: TiffValue| match value {
+        TiffValue::Byte(v) => Some(v),
+        TiffValue::Unsigned(v) => u8::try_from(v).ok(),
+        TiffValue::UnsignedBig(v) => u8::try_from(v).ok(),
+        _ => None,
+    };
+    let bytes = match decoder.find_tag(Tag::Unknown(tag)).ok()?? {
+        TiffValue::List(values) => values.into_iter().map(to_u8).collect::<Option<Vec<u8>>>()?,
+        value => vec![to_u8(value)?],
+    };
+    Some(Bytes::from(bytes))
+}
+
+/// エンコードされた画像のコンテナからICCプロファイルを取り出す。
+/// JPEG(APP2), PNG(iCCP), WebP(ICCP)はimg-partsで、TIFFはタグから読む。
+pub fn read_icc_profile(buf: &[u8]) -> Option<Bytes> {
+    match image::guess_format(buf).ok()? {
+        ImageFormat::Tiff => read_tiff_bytes_tag(buf, TIFF_TAG_ICC_PROFILE),
+        _ => DynImage::from_bytes(Bytes::copy_from_slice(buf))
+            .ok()??
+            .icc_profile(),
+    }
+}
+
+/// HEIC/HEIFの拡張子を持つか。
+pub fn is_heic_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("heic") || ext.eq_ignore_ascii_case("heif"))
+}
+
+/// `magick` featureなしでビルドした場合、HEICは読めない。
+#[cfg(not(feature = "magick"))]
+fn decode_heic(_buf: &[u8]) -> Result<ImageData> {
+    anyhow::bail!(AppError::UnsupportedFormat(
+        "HEIC/HEIF support not compiled in. rebuild with `--features magick`.".to_string()
+    ))
+}
+
+/// 受け取ったパスのファイルを読んで画像データとして返す。フォーマットはファイルの中身から判定する。
+/// 画像全体を一度にデコードするので、画像の大きさに比例したメモリを使う。
+/// HEIC形式はimageクレートで読めないため、その場合は`magick` featureが必要となる。
+/// EXIFの向きはピクセルに適用し、EXIFの書き換えは残すときに`Metadata::filtered`で行う。
+pub fn read_image<P: AsRef<Path>>(path: P) -> Result<ImageData> {
+    let path = path.as_ref();
+    let buf = read_binary(path)?;
+    // heicフォーマットはImageMagickで直接ピクセルにデコードする。
+    if is_heic_path(path) {
+        return decode_heic(&buf);
+    }
+    let icc = read_icc_profile(&buf);
+    let mut metadata = read_metadata(&buf);
+    // FilterProcessの座標が表示される向きの画像を指すように、向きを適用しておく。
+    let orientation = match image::guess_format(&buf) {
+        Ok(ImageFormat::Tiff) => read_tiff_orientation(&buf),
+        _ => metadata.exif.as_deref().and_then(exif_orientation),
+    }
+    .unwrap_or(1);
+    let image =
+        image::load_from_memory(&buf).map_err(|err| match err {
+            ImageError::Unsupported(err) => anyhow::Error::new(AppError::UnsupportedFormat(
+                format!("unsupported image format: {} ({})", path.display(), err),
+            )),
+            err => anyhow::Error::new(err).context(AppError::Decode(format!(
+                "failed to decode {}",
+                path.display()
+            ))),
+        })?;
+    let image = apply_orientation(image, orientation);
+    metadata.oriented = orientation != 1;
+    Ok(ImageData::new(
+        PixelBuffer::from_dynamic(image),
+        icc,
+        metadata,
+    ))
+}
+
+/// TIFFのOrientationタグの値を読む。
+fn read_tiff_orientation(buf: &[u8]) -> Option<u32> {
+    TiffDecoder::new(Cursor::new(buf))
+        .ok()?
+        .find_tag_unsigned(Tag::Orientation)
+        .ok()?
+}
+
+/// EXIFの向き(1-8)に従って、ビューアで表示される向きにピクセルを並べ替える。
+pub fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
     }
 }
@@ -0,0 +1,58 @@
+//! 画像の一部の領域にフィルタをかけるライブラリ。
+//!
+//! 画像を読み込み、フィルタと領域の組を[`Pipeline`]に並べて適用し、指定のフォーマットで書き出す。
+//! 複数のファイルは[`batch`]で展開し、並列に処理できる。出力先の名前は[`naming`]のテンプレートで決める。
+//! コマンドラインツールはこのライブラリの利用者の1つである。
+//!
+//! ```
+//! use my_img_utilities::filter::{gaussian::{GaussianFilter, GaussianFilterOption}, AppFilter};
+//! use my_img_utilities::{
+//!     encode_image, BlendMode, BlendOptions, BorderPolicy, EncodeOptions, FilterProcess,
+//!     Metadata, OutputFormat, Pipeline, PixelBuffer, Region,
+//! };
+//!
+//! let img = PixelBuffer::U8(image::RgbaImage::from_fn(32, 32, |x, y| {
+//!     image::Rgba([(x * 8) as u8, (y * 8) as u8, 128, 255])
+//! }));
+//! let blur = AppFilter::new(GaussianFilter::new(GaussianFilterOption::new(None, 2.0)));
+//! let region = Region::Ellipse { x: 4, y: 4, width: 24, height: 24 };
+//! let pipeline = Pipeline::new(BorderPolicy::Mirror).push(FilterProcess::new(
+//!     blur,
+//!     region,
+//!     BlendOptions::new(2, 0.8, BlendMode::Normal),
+//! ));
+//! let out = pipeline.run(img).unwrap();
+//!
+//! let png = encode_image(
+//!     out,
+//!     OutputFormat::Png,
+//!     &EncodeOptions::default(),
+//!     None,
+//!     &Metadata::default(),
+//! )
+//! .unwrap();
+//! assert!(png.starts_with(b"\x89PNG"));
+//! ```
+
+pub mod arithmetic;
+pub mod batch;
+pub mod error;
+pub mod filter;
+pub mod io;
+pub mod metadata;
+#[cfg(feature = "magick")]
+mod my_magick;
+pub mod naming;
+pub mod output;
+pub mod pipeline;
+pub mod process;
+pub mod region;
+
+pub use error::AppError;
+pub use filter::AppFilter;
+pub use io::{read_image, write_binary, ImageData};
+pub use metadata::{Metadata, MetadataPolicy};
+pub use output::{encode_image, EncodeOptions, OutputFormat};
+pub use pipeline::{FilterProcess, Pipeline};
+pub use process::{BlendMode, BlendOptions, BorderPolicy, PixelBuffer};
+pub use region::Region;
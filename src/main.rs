@@ -1,62 +1,76 @@
-use std::fs::File;
-
 use anyhow::Result;
 use clap::Parser;
 use cli::{clap_parser::parser::AppArgs, interactive::input::input_on_console};
-use image::codecs::jpeg::JpegEncoder;
-use img_parts::{jpeg::Jpeg, ImageICC};
+use my_img_utilities::batch::{process_file, BatchItem, FileOutcome};
+use my_img_utilities::{error::exit_code, filter::registry, Pipeline};
 
-use crate::{
-    cli::interactive::input::{AppParams, FilterProcess},
-    io::{read_image, ImageData},
-    process::modify_part_of_img,
-};
+use crate::cli::batch::{default_jobs, process_batch};
 
-mod arithmetic;
 mod cli;
-mod filter;
-mod io;
-mod my_magick;
-mod process;
 
-fn main() -> Result<()> {
+/// エラーの種類ごとに終了コードを分けて終了する。コードは`AppError::exit_code`を参照。
+fn main() {
+    // ライブラリの警告 (メタデータの破棄や領域の切り詰めなど) を標準エラーに表示する
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"))
+        .format_timestamp(None)
+        .format_target(false)
+        .init();
     let app_args = AppArgs::parse();
-    let app_params = match input_on_console(&app_args) {
-        Ok(res) => res,
-        Err(err) => {
-            println!("{}", err);
-            std::process::exit(1);
-        }
-    };
+    if let Err(err) = run(&app_args) {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(exit_code(&err));
+    }
+}
+
+fn run(app_args: &AppArgs) -> Result<()> {
+    if let Some(threads) = app_args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .build_global()?;
+    }
+    if app_args.list_filters {
+        print_filters();
+        return Ok(());
+    }
+    let app_params = input_on_console(app_args)?;
     println!("applying following filters");
     println!("{}", app_params);
 
-    let AppParams {
-        filepath,
-        output,
-        processes,
-    } = app_params;
-    let ImageData {
-        buffer: mut img,
-        icc,
-    } = read_image(&filepath)?;
-
-    // フィルタをピクセル列に繰り返し適用
-    for filter_process in processes.iter() {
-        let FilterProcess {
-            filter,
-            x,
-            y,
-            width,
-            height,
-        } = filter_process;
-        img = modify_part_of_img(img, *x, *y, *width, *height, filter).unwrap();
+    let pipeline = Pipeline {
+        processes: app_params.processes.clone(),
+        border: app_params.border,
+    };
+    let naming = app_params.naming();
+    if app_params.is_batch() {
+        let jobs = app_args
+            .jobs
+            .map_or_else(default_jobs, |jobs| jobs as usize);
+        return process_batch(&app_params, &pipeline, &naming, jobs);
+    }
+    // icc profileとメタデータを引き継ぎながら指定のフォーマットでファイルに書き出す
+    let outcome = process_file(
+        &BatchItem::single(&app_params.filepath[0]),
+        &naming,
+        &pipeline,
+        &app_params.encode,
+        &app_params.metadata,
+    )?;
+    match outcome {
+        FileOutcome::Written(output) => println!("saved: {}", output.display()),
+        FileOutcome::Skipped(reason) => println!("skipped: {}", reason),
     }
-    // icc profileを引き継ぎながらファイルに書き出す
-    let mut jpeg_buf = Vec::<u8>::new();
-    img.write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg_buf, 85))?;
-    let mut jpeg = Jpeg::from_bytes(jpeg_buf.into())?;
-    jpeg.set_icc_profile(icc);
-    jpeg.encoder().write_to(File::create(output)?)?;
     Ok(())
 }
+
+/// 登録されたフィルタとオプションを一覧にする。
+fn print_filters() {
+    for entry in registry::entries() {
+        println!("{}", entry);
+        if !entry.aliases.is_empty() {
+            println!("    aliases: {}", entry.aliases.join(", "));
+        }
+        for spec in entry.options.iter() {
+            println!("    {:<12} {} ({})", spec.name, spec.help, spec.describe());
+        }
+    }
+}
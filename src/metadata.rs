@@ -0,0 +1,359 @@
+use anyhow::Result;
+use exif::{experimental::Writer, Field, In, Tag, Value};
+use image::ImageFormat;
+use img_parts::jpeg::{markers, Jpeg, JpegSegment};
+use img_parts::png::{Png, PngChunk};
+use img_parts::riff::{RiffChunk, RiffContent};
+use img_parts::webp::{
+    WebP, CHUNK_ALPH, CHUNK_EXIF, CHUNK_ICCP, CHUNK_VP8L, CHUNK_VP8X, CHUNK_XMP,
+};
+use img_parts::{Bytes, DynImage, ImageEXIF};
+use serde_derive::{Deserialize, Serialize};
+use std::io::Cursor;
+
+use crate::io::read_tiff_bytes_tag;
+
+/// TIFFでXMPを格納するタグ番号。
+pub(crate) const TIFF_TAG_XMP: u16 = 700;
+/// JPEGのAPP1セグメントでXMPを示す識別子。
+const JPEG_XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// PNGのiTXtチャンクでXMPを示すキーワード。
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+const PNG_CHUNK_ITXT: [u8; 4] = *b"iTXt";
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+
+/// カメラ本体やレンズを特定できるシリアル番号のタグ。
+/// メーカーノートにもシリアル番号が含まれるため一緒に取り除く。
+const SERIAL_TAGS: [Tag; 4] = [
+    Tag::BodySerialNumber,
+    Tag::LensSerialNumber,
+    Tag::MakerNote,
+    // DNGのCameraSerialNumber
+    Tag(exif::Context::Tiff, 0xc62f),
+];
+
+/// 画像に付随するICCプロファイル以外のメタデータ。
+/// EXIFは先頭の`Exif\0\0`を除いたTIFF構造のバイナリ、XMPはXMLパケットをそのまま持つ。
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub exif: Option<Bytes>,
+    pub xmp: Option<Bytes>,
+    /// ピクセルにEXIFの向きを適用済みか。EXIFを残すときに向きを正位置(1)に書き換える。
+    pub oriented: bool,
+    /// EXIFを読めない形式(TIFF)から読んだか。EXIFを残す設定のときに警告する。
+    pub exif_unsupported: bool,
+}
+
+/// 出力にメタデータをどう引き継ぐかの設定。デフォルトではすべて破棄する。
+/// GPS情報やシリアル番号を取り除くときは、XMPの中にも入りうるためXMPは残さない。
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataPolicy {
+    /// EXIF/XMPを引き継ぐかどうか。
+    pub keep: bool,
+    /// GPS情報を取り除く。XMPも破棄する。
+    pub strip_gps: bool,
+    /// カメラやレンズのシリアル番号を取り除く。XMPも破棄する。
+    pub strip_serials: bool,
+}
+impl std::fmt::Display for MetadataPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.keep {
+            return write!(f, "strip all");
+        }
+        match (self.strip_gps, self.strip_serials) {
+            (false, false) => write!(f, "keep all"),
+            (true, false) => write!(f, "keep (strip gps, drop xmp)"),
+            (false, true) => write!(f, "keep (strip serials, drop xmp)"),
+            (true, true) => write!(f, "keep (strip gps, serials, drop xmp)"),
+        }
+    }
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none()
+    }
+    /// 設定に従ってメタデータを取捨選択する。向きを適用済みなら、残すEXIFの向きを正位置に戻す。
+    /// 書き換えられないEXIFは警告を出して破棄する。
+    /// XMPの中にもGPS情報やシリアル番号が入りうるため、部分的に取り除く場合はXMPごと破棄する。
+    pub fn filtered(&self, policy: &MetadataPolicy) -> Result<Metadata> {
+        if !policy.keep {
+            return Ok(Metadata::default());
+        }
+        if self.exif_unsupported {
+            log::warn!("EXIF of TIFF input cannot be kept. only XMP is carried over.");
+        }
+        let exif = match (&self.exif, self.oriented) {
+            (Some(exif), true) => reset_orientation(exif).unwrap_or_else(|err| {
+                log::warn!(
+                    "failed to reset the EXIF orientation ({:#}). EXIF is dropped.",
+                    err
+                );
+                None
+            }),
+            (exif, _) => exif.clone(),
+        };
+        if !policy.strip_gps && !policy.strip_serials {
+            return Ok(Metadata {
+                exif,
+                xmp: self.xmp.clone(),
+                ..Default::default()
+            });
+        }
+        let exif = match &exif {
+            Some(exif) => rewrite_exif(exif, |field| {
+                let is_gps = field.tag.context() == exif::Context::Gps;
+                let is_serial = SERIAL_TAGS.contains(&field.tag);
+                if (policy.strip_gps && is_gps) || (policy.strip_serials && is_serial) {
+                    None
+                } else {
+                    Some(field.clone())
+                }
+            })?,
+            None => None,
+        };
+        if self.xmp.is_some() {
+            log::warn!(
+                "XMP metadata is dropped since it may also contain GPS location or serial numbers."
+            );
+        }
+        Ok(Metadata {
+            exif,
+            ..Default::default()
+        })
+    }
+}
+
+/// EXIFのフィールドを1つずつ選別・変換して書き直す。サムネイルはそのまま引き継ぐ。
+/// 主画像のフィールドが1つも残らなければNoneを返す。
+pub(crate) fn rewrite_exif<F>(exif: &[u8], mut map: F) -> Result<Option<Bytes>>
+where
+    F: FnMut(&Field) -> Option<Field>,
+{
+    let exif = exif::Reader::new().read_raw(exif.to_vec())?;
+    let fields = exif
+        .fields()
+        .filter(|field| !matches!(field.value, Value::Unknown(..)))
+        .filter_map(&mut map)
+        .collect::<Vec<Field>>();
+    if !fields.iter().any(|field| field.ifd_num == In::PRIMARY) {
+        return Ok(None);
+    }
+    let mut writer = Writer::new();
+    for field in fields.iter() {
+        writer.push_field(field);
+    }
+    let thumbnail = exif_thumbnail(&exif);
+    if let Some(thumbnail) = thumbnail {
+        writer.set_jpeg(thumbnail, In::THUMBNAIL);
+    }
+    let mut buf = Cursor::new(Vec::<u8>::new());
+    writer.write(&mut buf, exif.little_endian())?;
+    Ok(Some(Bytes::from(buf.into_inner())))
+}
+
+/// EXIFの向き(Orientation)の値を取り出す。
+pub(crate) fn exif_orientation(exif: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new().read_raw(exif.to_vec()).ok()?;
+    exif.get_field(Tag::Orientation, In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+/// EXIFから撮影日時を取り出す。DateTimeOriginal、DateTimeDigitized、DateTimeの順に探す。
+pub(crate) fn exif_capture_time(exif: &[u8]) -> Option<exif::DateTime> {
+    let exif = exif::Reader::new().read_raw(exif.to_vec()).ok()?;
+    [Tag::DateTimeOriginal, Tag::DateTimeDigitized, Tag::DateTime]
+        .into_iter()
+        .find_map(|tag| match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Ascii(values) => exif::DateTime::from_ascii(values.first()?).ok(),
+            _ => None,
+        })
+}
+
+/// EXIFの向きを正位置(1)に書き換える。ピクセルを回転済みにしたあとで使う。
+fn reset_orientation(exif: &[u8]) -> Result<Option<Bytes>> {
+    rewrite_exif(exif, |field| {
+        let mut field = field.clone();
+        if field.tag == Tag::Orientation {
+            field.value = Value::Short(vec![1]);
+        }
+        Some(field)
+    })
+}
+
+/// EXIFに埋め込まれたJPEGサムネイルを取り出す。
+fn exif_thumbnail(exif: &exif::Exif) -> Option<&[u8]> {
+    let offset = exif
+        .get_field(Tag::JPEGInterchangeFormat, In::THUMBNAIL)?
+        .value
+        .get_uint(0)? as usize;
+    let length = exif
+        .get_field(Tag::JPEGInterchangeFormatLength, In::THUMBNAIL)?
+        .value
+        .get_uint(0)? as usize;
+    exif.buf().get(offset..offset.checked_add(length)?)
+}
+
+/// エンコードされた画像のコンテナからEXIFとXMPを取り出す。
+/// TIFFはXMPのみ対応する（EXIFは画像本体のIFDと一体になっているため）。EXIFを残す設定なら`filtered`で警告する。
+pub fn read_metadata(buf: &[u8]) -> Metadata {
+    match image::guess_format(buf) {
+        Ok(ImageFormat::Tiff) => Metadata {
+            xmp: read_tiff_bytes_tag(buf, TIFF_TAG_XMP),
+            exif_unsupported: true,
+            ..Default::default()
+        },
+        _ => match DynImage::from_bytes(Bytes::copy_from_slice(buf)) {
+            Ok(Some(DynImage::Jpeg(jpeg))) => Metadata {
+                exif: jpeg.exif(),
+                xmp: jpeg_xmp(&jpeg),
+                ..Default::default()
+            },
+            Ok(Some(DynImage::Png(png))) => Metadata {
+                exif: png.exif(),
+                xmp: png_xmp(&png),
+                ..Default::default()
+            },
+            Ok(Some(DynImage::WebP(webp))) => Metadata {
+                exif: webp_exif(&webp),
+                xmp: webp
+                    .chunk_by_id(CHUNK_XMP)
+                    .and_then(|chunk| chunk.content().data().cloned()),
+                ..Default::default()
+            },
+            _ => Metadata::default(),
+        },
+    }
+}
+
+fn jpeg_xmp(jpeg: &Jpeg) -> Option<Bytes> {
+    jpeg.segments_by_marker(markers::APP1)
+        .map(JpegSegment::contents)
+        .find(|contents| contents.starts_with(JPEG_XMP_PREFIX))
+        .map(|contents| contents.slice(JPEG_XMP_PREFIX.len()..))
+}
+
+/// iTXtチャンクのうちXMPのものを探す。圧縮されたものには対応しない。
+fn png_xmp(png: &Png) -> Option<Bytes> {
+    png.chunks_by_type(PNG_CHUNK_ITXT).find_map(|chunk| {
+        let contents = chunk.contents();
+        let rest = contents
+            .strip_prefix(PNG_XMP_KEYWORD)?
+            .strip_prefix(b"\0")?;
+        // 圧縮フラグ, 圧縮方式, 言語タグ\0, 翻訳キーワード\0 の後にテキストが続く
+        let (&compressed, rest) = rest.split_first()?;
+        if compressed != 0 {
+            return None;
+        }
+        let rest = rest.get(1..)?;
+        let lang_end = rest.iter().position(|&b| b == 0)?;
+        let rest = &rest[lang_end + 1..];
+        let keyword_end = rest.iter().position(|&b| b == 0)?;
+        let offset = contents.len() - rest.len() + keyword_end + 1;
+        Some(contents.slice(offset..))
+    })
+}
+
+/// WebPのEXIFチャンクは仕様上プレフィックスを持たないが、持つ実装もあるので両方に対応する。
+fn webp_exif(webp: &WebP) -> Option<Bytes> {
+    let data = webp.chunk_by_id(CHUNK_EXIF)?.content().data()?;
+    Some(strip_exif_prefix(data.clone()))
+}
+
+/// 先頭に`Exif\0\0`が付いている場合は取り除き、TIFF形式のEXIFにする。
+pub(crate) fn strip_exif_prefix(data: Bytes) -> Bytes {
+    if data.starts_with(EXIF_PREFIX) {
+        data.slice(EXIF_PREFIX.len()..)
+    } else {
+        data
+    }
+}
+
+pub(crate) fn set_jpeg_xmp(jpeg: &mut Jpeg, xmp: Option<&Bytes>) {
+    jpeg.segments_mut().retain(|segment| {
+        !(segment.marker() == markers::APP1 && segment.contents().starts_with(JPEG_XMP_PREFIX))
+    });
+    let Some(xmp) = xmp else {
+        return;
+    };
+    // セグメント長は2バイトで表されるため、収まらないものは拡張XMPが必要になる。
+    if JPEG_XMP_PREFIX.len() + xmp.len() + 2 > u16::MAX as usize {
+        log::warn!("XMP metadata is too large for a JPEG segment. it is dropped.");
+        return;
+    }
+    let contents = [JPEG_XMP_PREFIX, xmp].concat();
+    let segment = JpegSegment::new_with_contents(markers::APP1, Bytes::from(contents));
+    // APPnセグメントの並びの最後に置く
+    let pos = jpeg
+        .segments()
+        .iter()
+        .position(|segment| !(markers::APP0..=markers::APP15).contains(&segment.marker()))
+        .unwrap_or(jpeg.segments().len());
+    jpeg.segments_mut().insert(pos, segment);
+}
+
+pub(crate) fn set_png_xmp(png: &mut Png, xmp: Option<&Bytes>) {
+    let is_xmp = |chunk: &PngChunk| {
+        chunk.kind() == PNG_CHUNK_ITXT && chunk.contents().starts_with(PNG_XMP_KEYWORD)
+    };
+    png.chunks_mut().retain(|chunk| !is_xmp(chunk));
+    if let Some(xmp) = xmp {
+        let contents = [PNG_XMP_KEYWORD, b"\0\0\0\0\0", xmp].concat();
+        let chunk = PngChunk::new(PNG_CHUNK_ITXT, Bytes::from(contents));
+        let pos = png.chunks().len().saturating_sub(1);
+        png.chunks_mut().insert(pos, chunk);
+    }
+}
+
+/// WebPにEXIFとXMPを設定し、VP8Xチャンクのフラグを付け直す。
+/// img-partsはVP8Xが既にある場合にフラグを更新しないため自前で処理する。
+pub(crate) fn set_webp_metadata(webp: &mut WebP, metadata: &Metadata) {
+    webp.remove_chunks_by_id(CHUNK_EXIF);
+    webp.remove_chunks_by_id(CHUNK_XMP);
+    if let Some(exif) = &metadata.exif {
+        let chunk = RiffChunk::new(CHUNK_EXIF, RiffContent::Data(exif.clone()));
+        webp.chunks_mut().push(chunk);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        let chunk = RiffChunk::new(CHUNK_XMP, RiffContent::Data(xmp.clone()));
+        webp.chunks_mut().push(chunk);
+    }
+    update_webp_vp8x(webp);
+}
+
+/// 現在のチャンク構成に合わせてVP8Xチャンクを作り直す。拡張機能が不要なら取り除く。
+fn update_webp_vp8x(webp: &mut WebP) {
+    let has_icc = webp.has_chunk(CHUNK_ICCP);
+    let has_exif = webp.has_chunk(CHUNK_EXIF);
+    let has_xmp = webp.has_chunk(CHUNK_XMP);
+    // 透過情報は元のVP8X、ALPHチャンク、VP8Lヘッダのいずれかから判定する
+    let chunk_data = |id| {
+        webp.chunk_by_id(id)
+            .and_then(|chunk| chunk.content().data().cloned())
+    };
+    let alpha_flag = |data: Option<Bytes>, index: usize| {
+        data.and_then(|data| data.get(index).copied())
+            .is_some_and(|byte| byte & 0x10 != 0)
+    };
+    let has_alpha = alpha_flag(chunk_data(CHUNK_VP8X), 0)
+        || webp.has_chunk(CHUNK_ALPH)
+        || alpha_flag(chunk_data(CHUNK_VP8L), 4);
+    webp.remove_chunks_by_id(CHUNK_VP8X);
+    if !(has_icc || has_exif || has_xmp) {
+        return;
+    }
+    let Some((width, height)) = webp.dimensions() else {
+        return;
+    };
+    let flags = (has_icc as u8) << 5
+        | (has_alpha as u8) << 4
+        | (has_exif as u8) << 3
+        | (has_xmp as u8) << 2;
+    let mut data = vec![flags, 0, 0, 0];
+    data.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    data.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+    let chunk = RiffChunk::new(CHUNK_VP8X, RiffContent::Data(Bytes::from(data)));
+    webp.chunks_mut().insert(0, chunk);
+}
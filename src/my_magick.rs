@@ -1,21 +1,100 @@
-use anyhow::Result;
-use magick_rust::{magick_wand_genesis, MagickWand};
+use anyhow::{ensure, Context, Result};
+use image::{DynamicImage, ImageBuffer};
+use img_parts::Bytes;
+use magick_rust::{bindings, magick_wand_genesis, MagickWand};
+use std::ffi::{c_void, CString};
 use std::sync::Once;
 
+use crate::error::AppError;
+use crate::io::ImageData;
+use crate::metadata::{strip_exif_prefix, Metadata};
+use crate::process::PixelBuffer;
+
 // Used to make sure MagickWand is initialized exactly once. Note that we
 // do not bother shutting down, we simply exit when we're done.
 static START: Once = Once::new();
 
-/// 指定したパスのファイルをImageMagickを用いてJPGに変換する。変換後のバイナリを返す。
-pub(crate) fn convert_to_jpeg_binary(buf: &[u8]) -> Result<Vec<u8>> {
+/// HEICのバイナリをImageMagickでデコードし、画像データとして返す。
+/// ピクセルは16bitのまま書き出すので、JPEGを経由した再圧縮は起きない。
+/// 向きは適用済みにし、ICCプロファイルとEXIF, XMPは引き継ぐ。
+pub(crate) fn decode_heic(buf: &[u8]) -> Result<ImageData> {
+    read_heic(buf).context(AppError::Magick(
+        "failed to decode HEIC with ImageMagick".to_string(),
+    ))
+}
+
+fn read_heic(buf: &[u8]) -> Result<ImageData> {
     START.call_once(|| {
         magick_wand_genesis();
     });
-    let mut wand = MagickWand::new();
+    let wand = MagickWand::new();
     wand.read_image_blob(buf)?;
-    wand.set_compression_quality(90)?;
     wand.auto_orient();
-    wand.profile_image("!icc,*", None)?;
-    let res = wand.write_image_blob("jpeg")?;
-    Ok(res)
+    let (width, height) = (wand.get_image_width(), wand.get_image_height());
+    let image = if wand.get_image_alpha_channel() {
+        let pixels = export_pixels_u16(&wand, width, height, "RGBA")?;
+        ImageBuffer::from_raw(width as u32, height as u32, pixels).map(DynamicImage::ImageRgba16)
+    } else {
+        let pixels = export_pixels_u16(&wand, width, height, "RGB")?;
+        ImageBuffer::from_raw(width as u32, height as u32, pixels).map(DynamicImage::ImageRgb16)
+    };
+    let Some(image) = image else {
+        anyhow::bail!("failed to build an image from HEIC pixels.");
+    };
+    // auto_orientでピクセルは回転済みなので、EXIFの向きは残すときに正位置に戻す。
+    let metadata = Metadata {
+        exif: image_profile(&wand, "exif").map(strip_exif_prefix),
+        xmp: image_profile(&wand, "xmp"),
+        oriented: true,
+        ..Default::default()
+    };
+    Ok(ImageData::new(
+        PixelBuffer::from_dynamic(image),
+        image_profile(&wand, "icc"),
+        metadata,
+    ))
+}
+
+/// 画像全体のピクセルを16bitの値として`map`で指定したチャンネル順に書き出す。
+fn export_pixels_u16(
+    wand: &MagickWand,
+    width: usize,
+    height: usize,
+    map: &str,
+) -> Result<Vec<u16>> {
+    let c_map = CString::new(map)?;
+    let mut pixels = vec![0u16; width * height * map.len()];
+    let res = unsafe {
+        bindings::MagickExportImagePixels(
+            wand.wand,
+            0,
+            0,
+            width,
+            height,
+            c_map.as_ptr(),
+            bindings::StorageType_ShortPixel,
+            pixels.as_mut_ptr() as *mut c_void,
+        )
+    };
+    ensure!(
+        res == bindings::MagickBooleanType_MagickTrue,
+        "failed to export HEIC pixels."
+    );
+    Ok(pixels)
+}
+
+/// 名前を指定して画像に付いているプロファイル(icc, exif, xmpなど)を取り出す。
+fn image_profile(wand: &MagickWand, name: &str) -> Option<Bytes> {
+    let c_name = CString::new(name).ok()?;
+    let mut length = 0;
+    unsafe {
+        let profile = bindings::MagickGetImageProfile(wand.wand, c_name.as_ptr(), &mut length);
+        if profile.is_null() {
+            return None;
+        }
+        let res = (length > 0)
+            .then(|| Bytes::copy_from_slice(std::slice::from_raw_parts(profile, length)));
+        bindings::MagickRelinquishMemory(profile as *mut c_void);
+        res
+    }
 }
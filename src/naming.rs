@@ -0,0 +1,438 @@
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR_STR};
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+
+use crate::error::AppError;
+
+/// 出力ファイル名のテンプレートのデフォルト。
+pub const DEFAULT_TEMPLATE: &str = "{dir}/{stem}_filtered.{ext}";
+
+/// テンプレートに書ける値。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Stem,
+    Ext,
+    Dir,
+    Index,
+    Date,
+    Width,
+    Height,
+    Filters,
+}
+impl Field {
+    const ALL: [(&'static str, Field); 8] = [
+        ("stem", Field::Stem),
+        ("ext", Field::Ext),
+        ("dir", Field::Dir),
+        ("index", Field::Index),
+        ("date", Field::Date),
+        ("width", Field::Width),
+        ("height", Field::Height),
+        ("filters", Field::Filters),
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// `{stem}_filtered.{ext}`のような出力ファイル名のテンプレート。`/`で区切ってディレクトリも作れる。
+/// 値が空になった階層は取り除くので、`{dir}/`は入力がディレクトリの直下なら消える。
+///
+/// - `{stem}`: 入力ファイル名の拡張子を除いた部分
+/// - `{ext}`: 出力フォーマットの拡張子
+/// - `{dir}`: 入力ディレクトリから見たファイルのあるサブディレクトリ
+/// - `{index}`: 入力の中での通し番号 (1から。桁数は入力の数に揃える)
+/// - `{date}`: EXIFの撮影日時 (`20240501_134510`の形。なければ`undated`)
+/// - `{width}`, `{height}`: 画像の大きさ
+/// - `{filters}`: 適用するフィルタの名前を`-`でつないだもの
+///
+/// ```
+/// use my_img_utilities::naming::{NameFields, OutputTemplate};
+///
+/// let template: OutputTemplate = "{dir}/{stem}_{width}x{height}.{ext}".parse().unwrap();
+/// let fields = NameFields {
+///     stem: "photo".to_string(),
+///     ext: "png",
+///     width: 640,
+///     height: 480,
+///     ..Default::default()
+/// };
+/// assert_eq!(template.render(&fields), std::path::Path::new("photo_640x480.png"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct OutputTemplate {
+    source: String,
+    components: Vec<Vec<Segment>>,
+}
+impl OutputTemplate {
+    /// プレースホルダを含むか。含まなければ固定のパスとして扱える。
+    pub fn has_placeholders(&self) -> bool {
+        self.components
+            .iter()
+            .flatten()
+            .any(|segment| matches!(segment, Segment::Field(_)))
+    }
+    /// 画像を読み込まないと分からない値 (大きさと撮影日時) を使うか。
+    pub fn needs_image(&self) -> bool {
+        self.components.iter().flatten().any(|segment| {
+            matches!(
+                segment,
+                Segment::Field(Field::Width | Field::Height | Field::Date)
+            )
+        })
+    }
+    /// 値を埋めてパスにする。
+    pub fn render(&self, fields: &NameFields) -> PathBuf {
+        let mut path = match self.source.starts_with(std::path::is_separator) {
+            true => PathBuf::from(MAIN_SEPARATOR_STR),
+            false => PathBuf::new(),
+        };
+        for component in self.components.iter() {
+            let name = component
+                .iter()
+                .map(|segment| match segment {
+                    Segment::Literal(text) => text.clone(),
+                    Segment::Field(field) => fields.value(*field),
+                })
+                .collect::<String>();
+            if !name.is_empty() {
+                path.push(name);
+            }
+        }
+        path
+    }
+}
+impl Default for OutputTemplate {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE.parse().unwrap()
+    }
+}
+impl FromStr for OutputTemplate {
+    type Err = AppError;
+    fn from_str(source: &str) -> std::result::Result<Self, Self::Err> {
+        let components = source
+            .split(std::path::is_separator)
+            .map(parse_component)
+            .collect::<std::result::Result<Vec<_>, String>>()
+            .map_err(|message| {
+                AppError::InvalidOption(format!("invalid output template {}: {}", source, message))
+            })?;
+        Ok(Self {
+            source: source.to_string(),
+            components,
+        })
+    }
+}
+impl TryFrom<String> for OutputTemplate {
+    type Error = AppError;
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+impl From<OutputTemplate> for String {
+    fn from(value: OutputTemplate) -> Self {
+        value.source
+    }
+}
+impl std::fmt::Display for OutputTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// パスの1階層分を文字列とプレースホルダに分ける。
+fn parse_component(component: &str) -> std::result::Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut rest = component;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            segments.push(Segment::Literal(rest[..start].to_string()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| "unclosed {".to_string())?;
+        let name = &rest[start + 1..start + end];
+        let field = Field::ALL
+            .iter()
+            .find(|(field_name, _)| *field_name == name)
+            .map(|(_, field)| *field)
+            .ok_or_else(|| {
+                let names = Field::ALL.map(|(name, _)| name).join(", ");
+                format!("unknown placeholder {{{}}} (expected {})", name, names)
+            })?;
+        segments.push(Segment::Field(field));
+        rest = &rest[start + end + 1..];
+    }
+    if rest.contains('}') {
+        return Err("unmatched }".to_string());
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest.to_string()));
+    }
+    Ok(segments)
+}
+
+/// テンプレートに埋める値。
+#[derive(Debug, Default)]
+pub struct NameFields {
+    pub stem: String,
+    pub ext: &'static str,
+    pub dir: PathBuf,
+    /// 1から数えた入力の中での番号。
+    pub index: usize,
+    /// 入力の数。`index`の桁数を揃えるのに使う。
+    pub total: usize,
+    pub date: Option<exif::DateTime>,
+    pub width: u32,
+    pub height: u32,
+    pub filters: Vec<&'static str>,
+}
+impl NameFields {
+    fn value(&self, field: Field) -> String {
+        match field {
+            Field::Stem => self.stem.clone(),
+            Field::Ext => self.ext.to_string(),
+            Field::Dir => self.dir.to_string_lossy().to_string(),
+            Field::Index => {
+                let width = self.total.max(1).to_string().len();
+                format!("{:0width$}", self.index)
+            }
+            Field::Date => match &self.date {
+                Some(date) => format!(
+                    "{:04}{:02}{:02}_{:02}{:02}{:02}",
+                    date.year, date.month, date.day, date.hour, date.minute, date.second
+                ),
+                None => "undated".to_string(),
+            },
+            Field::Width => self.width.to_string(),
+            Field::Height => self.height.to_string(),
+            Field::Filters => self.filters.join("-"),
+        }
+    }
+}
+
+/// 出力先にすでにファイルがあるときの扱い。デフォルトでは番号を付けて別の名前で書き出す。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// replace the existing file.
+    Overwrite,
+    /// leave the existing file and do not write.
+    Skip,
+    /// write to the first free name with _1, _2, ... appended.
+    #[default]
+    AutoNumber,
+}
+impl std::fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Overwrite => write!(f, "overwrite"),
+            Self::Skip => write!(f, "skip"),
+            Self::AutoNumber => write!(f, "auto-number"),
+        }
+    }
+}
+
+/// 出力先を決めた結果。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputTarget {
+    Write(PathBuf),
+    /// 書き出さない。理由を持つ。
+    Skip(String),
+}
+
+/// 出力先の決め方。パスが決まっていればそれを、なければテンプレートを基準のディレクトリの下で使う。
+/// 同じ実行の中で決めた出力先を覚えておき、既存のファイルと同じように重なりを扱う。
+#[derive(Debug, Default)]
+pub struct OutputNaming {
+    pub output: Option<PathBuf>,
+    pub base_dir: PathBuf,
+    pub template: OutputTemplate,
+    pub on_conflict: ConflictPolicy,
+    /// 出力先と、そこに書き出す入力。
+    claimed: Mutex<HashMap<PathBuf, PathBuf>>,
+}
+impl OutputNaming {
+    pub fn new(
+        output: Option<PathBuf>,
+        base_dir: PathBuf,
+        template: OutputTemplate,
+        on_conflict: ConflictPolicy,
+    ) -> Self {
+        Self {
+            output,
+            base_dir,
+            template,
+            on_conflict,
+            claimed: Mutex::default(),
+        }
+    }
+    /// 出力先を決めるのに画像の中身が要るか。
+    pub fn needs_image(&self) -> bool {
+        self.output.is_none() && self.template.needs_image()
+    }
+    /// `input`の出力先を決めて確保する。同じ実行で確保済みの出力先は`overwrite`でも上書きせずエラーにする。
+    pub fn claim(&self, input: &Path, fields: &NameFields) -> Result<OutputTarget> {
+        let path = match &self.output {
+            Some(output) => output.clone(),
+            None => self.base_dir.join(self.template.render(fields)),
+        };
+        let mut claimed = self.claimed.lock().unwrap_or_else(PoisonError::into_inner);
+        let target = match (self.on_conflict, claimed.get(&path)) {
+            (ConflictPolicy::Overwrite, Some(other)) => bail!(
+                "output {} is also written by {}",
+                path.display(),
+                other.display()
+            ),
+            (ConflictPolicy::Skip, Some(other)) => {
+                return Ok(OutputTarget::Skip(format!(
+                    "{} is also written by {}",
+                    path.display(),
+                    other.display()
+                )))
+            }
+            (ConflictPolicy::Skip, None) if path.exists() => {
+                return Ok(OutputTarget::Skip(format!(
+                    "{} already exists",
+                    path.display()
+                )))
+            }
+            (ConflictPolicy::AutoNumber, _) => {
+                let taken = |path: &Path| claimed.contains_key(path) || path.exists();
+                let target = (0..u32::MAX)
+                    .map(|n| numbered(&path, n))
+                    .find(|candidate| !taken(candidate))
+                    .ok_or_else(|| anyhow!("no free name for {}", path.display()))?;
+                if target != path {
+                    log::warn!(
+                        "{} is already taken. writing to {} instead.",
+                        path.display(),
+                        target.display()
+                    );
+                }
+                target
+            }
+            _ => path,
+        };
+        claimed.insert(target.clone(), input.to_path_buf());
+        Ok(OutputTarget::Write(target))
+    }
+}
+
+/// ファイル名の拡張子の前に`_n`を付ける。0ならそのまま返す。
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(stem: &str) -> NameFields {
+        NameFields {
+            stem: stem.to_string(),
+            ext: "png",
+            ..Default::default()
+        }
+    }
+
+    /// テスト用の空のディレクトリを作る。
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("naming-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn naming(dir: &Path, template: &str, on_conflict: ConflictPolicy) -> OutputNaming {
+        OutputNaming::new(
+            None,
+            dir.to_path_buf(),
+            template.parse().unwrap(),
+            on_conflict,
+        )
+    }
+
+    #[test]
+    fn empty_components_are_dropped() {
+        let template: OutputTemplate = DEFAULT_TEMPLATE.parse().unwrap();
+        assert_eq!(template.render(&fields("a")), Path::new("a_filtered.png"));
+        let nested = NameFields {
+            dir: PathBuf::from("sub/dir"),
+            ..fields("a")
+        };
+        assert_eq!(
+            template.render(&nested),
+            Path::new("sub/dir/a_filtered.png")
+        );
+    }
+
+    #[test]
+    fn overwrite_replaces_existing_files_but_not_outputs_of_the_same_run() {
+        let dir = temp_dir("overwrite");
+        std::fs::write(dir.join("out.png"), b"").unwrap();
+        let naming = naming(&dir, "out.{ext}", ConflictPolicy::Overwrite);
+        assert_eq!(
+            naming.claim(Path::new("a.png"), &fields("a")).unwrap(),
+            OutputTarget::Write(dir.join("out.png"))
+        );
+        assert!(naming.claim(Path::new("b.png"), &fields("b")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skip_leaves_existing_files_and_outputs_of_the_same_run() {
+        let dir = temp_dir("skip");
+        std::fs::write(dir.join("a_filtered.png"), b"").unwrap();
+        let naming = naming(&dir, DEFAULT_TEMPLATE, ConflictPolicy::Skip);
+        assert!(matches!(
+            naming.claim(Path::new("a.png"), &fields("a")).unwrap(),
+            OutputTarget::Skip(_)
+        ));
+        assert_eq!(
+            naming.claim(Path::new("b.png"), &fields("b")).unwrap(),
+            OutputTarget::Write(dir.join("b_filtered.png"))
+        );
+        assert!(matches!(
+            naming
+                .claim(Path::new("other/b.png"), &fields("b"))
+                .unwrap(),
+            OutputTarget::Skip(_)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn auto_number_appends_the_first_free_number() {
+        let dir = temp_dir("auto-number");
+        std::fs::write(dir.join("a_filtered.png"), b"").unwrap();
+        let naming = naming(&dir, DEFAULT_TEMPLATE, ConflictPolicy::AutoNumber);
+        assert_eq!(
+            naming.claim(Path::new("a.png"), &fields("a")).unwrap(),
+            OutputTarget::Write(dir.join("a_filtered_1.png"))
+        );
+        assert_eq!(
+            naming
+                .claim(Path::new("other/a.png"), &fields("a"))
+                .unwrap(),
+            OutputTarget::Write(dir.join("a_filtered_2.png"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
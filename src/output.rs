@@ -0,0 +1,436 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use clap::ValueEnum;
+use image::codecs::{
+    bmp::BmpEncoder,
+    jpeg::JpegEncoder,
+    png::{CompressionType, FilterType, PngEncoder},
+    qoi::QoiEncoder,
+};
+use image::DynamicImage;
+use img_parts::{Bytes, DynImage, ImageEXIF, ImageICC};
+use serde_derive::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::Cursor;
+use std::path::Path;
+use tiff::encoder::{
+    colortype::{ColorType, RGB16, RGB8, RGBA16, RGBA8},
+    TiffEncoder, TiffValue,
+};
+use tiff::tags::{Tag, Type};
+
+use crate::error::AppError;
+use crate::io::TIFF_TAG_ICC_PROFILE;
+use crate::metadata::{set_jpeg_xmp, set_png_xmp, set_webp_metadata, Metadata, TIFF_TAG_XMP};
+use crate::process::PixelBuffer;
+
+/// 出力する画像フォーマット。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Tiff,
+    Bmp,
+    Qoi,
+}
+impl OutputFormat {
+    /// 拡張子からフォーマットを推定する。
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            "webp" => Some(Self::Webp),
+            "tif" | "tiff" => Some(Self::Tiff),
+            "bmp" => Some(Self::Bmp),
+            "qoi" => Some(Self::Qoi),
+            _ => None,
+        }
+    }
+    /// フォーマットに対応する標準の拡張子。
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Webp => "webp",
+            Self::Tiff => "tiff",
+            Self::Bmp => "bmp",
+            Self::Qoi => "qoi",
+        }
+    }
+    /// ICCプロファイルを埋め込めるフォーマットかどうか。
+    pub fn supports_icc(&self) -> bool {
+        !matches!(self, Self::Bmp | Self::Qoi)
+    }
+    /// EXIFを埋め込めるフォーマットかどうか。
+    pub fn supports_exif(&self) -> bool {
+        matches!(self, Self::Jpeg | Self::Png | Self::Webp)
+    }
+    /// XMPを埋め込めるフォーマットかどうか。
+    pub fn supports_xmp(&self) -> bool {
+        !matches!(self, Self::Bmp | Self::Qoi)
+    }
+    /// アルファチャンネルを保存できるフォーマットかどうか。
+    pub fn supports_alpha(&self) -> bool {
+        !matches!(self, Self::Jpeg)
+    }
+    /// 16bitのサブピクセルを保存できるフォーマットかどうか。
+    pub fn supports_16bit(&self) -> bool {
+        matches!(self, Self::Png | Self::Tiff)
+    }
+}
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// PNGの圧縮レベル。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+impl From<PngCompression> for CompressionType {
+    fn from(value: PngCompression) -> Self {
+        match value {
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Default => CompressionType::Default,
+            PngCompression::Best => CompressionType::Best,
+        }
+    }
+}
+
+/// 出力時のエンコード設定。フォーマットごとのオプションをまとめて持つ。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncodeOptions {
+    /// 出力フォーマット。指定がなければ出力パスの拡張子から決める。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<OutputFormat>,
+    /// JPEGの品質 (1-100)。
+    pub jpeg_quality: u8,
+    pub png_compression: PngCompression,
+    /// WebPを可逆圧縮で出力するかどうか。
+    pub webp_lossless: bool,
+    /// 非可逆WebPの品質 (0-100)。
+    pub webp_quality: f32,
+}
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            format: None,
+            jpeg_quality: 85,
+            png_compression: PngCompression::Default,
+            webp_lossless: false,
+            webp_quality: 80.0,
+        }
+    }
+}
+impl EncodeOptions {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            (1..=100).contains(&self.jpeg_quality),
+            AppError::InvalidOption("jpeg_quality must be between 1 and 100.".to_string())
+        );
+        ensure!(
+            (0.0..=100.0).contains(&self.webp_quality),
+            AppError::InvalidOption("webp_quality must be between 0 and 100.".to_string())
+        );
+        Ok(())
+    }
+    /// 実際に使う出力フォーマットを決める。
+    pub fn resolve_format<P: AsRef<Path>>(&self, output: P) -> Result<OutputFormat> {
+        self.format
+            .or_else(|| OutputFormat::from_path(&output))
+            .ok_or_else(|| {
+                anyhow!(AppError::UnsupportedFormat(format!(
+                    "cannot determine the output format from {}. specify --format.",
+                    output.as_ref().to_string_lossy()
+                )))
+            })
+    }
+    /// 指定したフォーマットで使われるオプションを文字列にする。
+    pub fn describe(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Jpeg => format!("{} (quality={})", format, self.jpeg_quality),
+            OutputFormat::Png => format!("{} (compression={:?})", format, self.png_compression),
+            OutputFormat::Webp if self.webp_lossless => format!("{} (lossless)", format),
+            OutputFormat::Webp => format!("{} (quality={})", format, self.webp_quality),
+            _ => format.to_string(),
+        }
+    }
+}
+/// TIFFのICCプロファイルタグはUNDEFINED型で書き込む必要がある。
+struct TiffIccProfile<'a>(&'a [u8]);
+impl TiffValue for TiffIccProfile<'_> {
+    const BYTE_LEN: u8 = 1;
+    const FIELD_TYPE: Type = Type::UNDEFINED;
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0)
+    }
+}
+
+/// ICCプロファイルとXMPを付けてTIFFにエンコードする。
+fn encode_tiff(image: &DynamicImage, icc: Option<&Bytes>, metadata: &Metadata) -> Result<Vec<u8>> {
+    let dimensions = (image.width(), image.height());
+    match image {
+        DynamicImage::ImageRgba8(img) => {
+            encode_tiff_with::<RGBA8>(img.as_raw(), dimensions, icc, metadata)
+        }
+        DynamicImage::ImageRgb16(img) => {
+            encode_tiff_with::<RGB16>(img.as_raw(), dimensions, icc, metadata)
+        }
+        DynamicImage::ImageRgba16(img) => {
+            encode_tiff_with::<RGBA16>(img.as_raw(), dimensions, icc, metadata)
+        }
+        _ => encode_tiff_with::<RGB8>(image.to_rgb8().as_raw(), dimensions, icc, metadata),
+    }
+}
+/// カラータイプを指定してTIFFにエンコードする。
+fn encode_tiff_with<C: ColorType>(
+    data: &[C::Inner],
+    (width, height): (u32, u32),
+    icc: Option<&Bytes>,
+    metadata: &Metadata,
+) -> Result<Vec<u8>>
+where
+    [C::Inner]: TiffValue,
+{
+    let mut buf = Vec::<u8>::new();
+    let mut encoder = TiffEncoder::new(Cursor::new(&mut buf))?;
+    let mut image = encoder.new_image::<C>(width, height)?;
+    if let Some(icc) = icc {
+        image
+            .encoder()
+            .write_tag(Tag::Unknown(TIFF_TAG_ICC_PROFILE), TiffIccProfile(icc))?;
+    }
+    if let Some(xmp) = &metadata.xmp {
+        image
+            .encoder()
+            .write_tag(Tag::Unknown(TIFF_TAG_XMP), &xmp[..])?;
+    }
+    image.write_data(data)?;
+    Ok(buf)
+}
+
+/// JPEG, PNG, WebPのバイナリにICCプロファイルとメタデータを埋め込む。
+fn embed_profiles(buf: Vec<u8>, icc: Option<&Bytes>, metadata: &Metadata) -> Result<Vec<u8>> {
+    let Some(mut image) = DynImage::from_bytes(buf.into())? else {
+        return Err(anyhow!(
+            "unsupported container for ICC profile and metadata."
+        ));
+    };
+    image.set_icc_profile(icc.cloned());
+    match &mut image {
+        DynImage::Jpeg(jpeg) => {
+            jpeg.set_exif(metadata.exif.clone());
+            set_jpeg_xmp(jpeg, metadata.xmp.as_ref());
+        }
+        DynImage::Png(png) => {
+            png.set_exif(metadata.exif.clone());
+            set_png_xmp(png, metadata.xmp.as_ref());
+        }
+        DynImage::WebP(webp) => set_webp_metadata(webp, metadata),
+    }
+    let mut buf = Vec::<u8>::new();
+    image.encoder().write_to(&mut buf)?;
+    Ok(buf)
+}
+
+/// 画像を指定したフォーマットでエンコードし、バイナリを返す。
+/// ICCプロファイルとメタデータは各コンテナの方法で埋め込む。埋め込めないものは破棄する。
+/// 透明なピクセルがなければアルファチャンネルは書き出さない。
+/// 16bitと浮動小数点数の画像は、対応するフォーマットでは16bitのまま、それ以外では8bitにして書き出す。
+/// バッファは受け取ってそのまま使い、アルファやビット深度を落とすときだけ変換した画像を作る。
+pub fn encode_image(
+    img: PixelBuffer,
+    format: OutputFormat,
+    options: &EncodeOptions,
+    icc: Option<&Bytes>,
+    metadata: &Metadata,
+) -> Result<Vec<u8>> {
+    encode_pixels(img, format, options, icc, metadata)
+        .with_context(|| AppError::Encode(format!("failed to encode the image as {}", format)))
+}
+
+fn encode_pixels(
+    img: PixelBuffer,
+    format: OutputFormat,
+    options: &EncodeOptions,
+    icc: Option<&Bytes>,
+    metadata: &Metadata,
+) -> Result<Vec<u8>> {
+    if icc.is_some() && !format.supports_icc() {
+        log::warn!(
+            "{} cannot carry an ICC profile. the profile is dropped.",
+            format
+        );
+    }
+    if metadata.exif.is_some() && !format.supports_exif() {
+        log::warn!("{} cannot carry EXIF metadata. it is dropped.", format);
+    }
+    if metadata.xmp.is_some() && !format.supports_xmp() {
+        log::warn!("{} cannot carry XMP metadata. it is dropped.", format);
+    }
+    let has_alpha = img.has_alpha();
+    if has_alpha && !format.supports_alpha() {
+        log::warn!("{} cannot carry an alpha channel. it is dropped.", format);
+    }
+    let has_alpha = has_alpha && format.supports_alpha();
+    let is_high_depth = img.is_high_depth() && format.supports_16bit();
+    if img.is_high_depth() && !format.supports_16bit() {
+        log::warn!(
+            "{} cannot carry 16-bit samples. it is reduced to 8-bit.",
+            format
+        );
+    }
+    let (width, height) = img.dimensions();
+    let image = DynamicImage::from(img);
+    let image = match (is_high_depth, has_alpha) {
+        (true, true) => DynamicImage::ImageRgba16(image.into_rgba16()),
+        (true, false) => DynamicImage::ImageRgb16(image.into_rgb16()),
+        (false, true) => DynamicImage::ImageRgba8(image.into_rgba8()),
+        (false, false) => DynamicImage::ImageRgb8(image.into_rgb8()),
+    };
+    let mut buf = Vec::<u8>::new();
+    match format {
+        OutputFormat::Jpeg => image.write_with_encoder(JpegEncoder::new_with_quality(
+            &mut buf,
+            options.jpeg_quality,
+        ))?,
+        OutputFormat::Png => image.write_with_encoder(PngEncoder::new_with_quality(
+            &mut buf,
+            options.png_compression.into(),
+            FilterType::Adaptive,
+        ))?,
+        OutputFormat::Webp => {
+            let encoder = if has_alpha {
+                webp::Encoder::from_rgba(image.as_bytes(), width, height)
+            } else {
+                webp::Encoder::from_rgb(image.as_bytes(), width, height)
+            };
+            // encode/encode_losslessは失敗するとpanicするため、エラーを返すencode_simpleを使う
+            let quality = match options.webp_lossless {
+                true => 75.0,
+                false => options.webp_quality,
+            };
+            let encoded = encoder
+                .encode_simple(options.webp_lossless, quality)
+                .map_err(|err| AppError::Encode(format!("failed to encode WebP: {:?}", err)))?;
+            buf.extend_from_slice(&encoded);
+        }
+        OutputFormat::Tiff => return encode_tiff(&image, icc, metadata),
+        OutputFormat::Bmp => image.write_with_encoder(BmpEncoder::new(&mut buf))?,
+        OutputFormat::Qoi => image.write_with_encoder(QoiEncoder::new(&mut buf))?,
+    }
+    let is_img_parts_container = matches!(
+        format,
+        OutputFormat::Jpeg | OutputFormat::Png | OutputFormat::Webp
+    );
+    if is_img_parts_container && (icc.is_some() || !metadata.is_empty()) {
+        embed_profiles(buf, icc, metadata)
+    } else {
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::read_icc_profile;
+
+    /// 中身を検査されないので、パターンで埋めたバイト列をプロファイルとして使う。
+    fn icc_profile(len: usize) -> Bytes {
+        Bytes::from((0..len).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>())
+    }
+
+    fn image() -> PixelBuffer {
+        PixelBuffer::U8(image::RgbaImage::from_fn(8, 8, |x, y| {
+            image::Rgba([(x * 32) as u8, (y * 32) as u8, 128, 255])
+        }))
+    }
+
+    #[test]
+    fn icc_profile_round_trip() {
+        let formats = [
+            OutputFormat::Jpeg,
+            OutputFormat::Png,
+            OutputFormat::Webp,
+            OutputFormat::Tiff,
+        ];
+        // JPEGではAPP2セグメント1つに収まらない大きさも試す
+        for len in [560, 70_000] {
+            let icc = icc_profile(len);
+            for format in formats {
+                let buf = encode_image(
+                    image(),
+                    format,
+                    &EncodeOptions::default(),
+                    Some(&icc),
+                    &Metadata::default(),
+                )
+                .unwrap();
+                assert_eq!(
+                    read_icc_profile(&buf).as_ref(),
+                    Some(&icc),
+                    "{} ({} bytes)",
+                    format,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn icc_profile_carries_across_format_pairs() {
+        let formats = [
+            OutputFormat::Jpeg,
+            OutputFormat::Png,
+            OutputFormat::Webp,
+            OutputFormat::Tiff,
+        ];
+        let icc = icc_profile(560);
+        let dir = std::env::temp_dir().join(format!("icc-pairs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for source in formats {
+            let buf = encode_image(
+                image(),
+                source,
+                &EncodeOptions::default(),
+                Some(&icc),
+                &Metadata::default(),
+            )
+            .unwrap();
+            let path = dir.join(format!("source.{}", source.extension()));
+            crate::io::write_binary(&path, &buf).unwrap();
+            // 入力として読み込んだプロファイルを、別のフォーマットの出力に引き継ぐ
+            for target in formats {
+                let input = crate::io::read_image(&path).unwrap();
+                let buf = encode_image(
+                    input.buffer,
+                    target,
+                    &EncodeOptions::default(),
+                    input.icc.as_ref(),
+                    &input.metadata,
+                )
+                .unwrap();
+                assert_eq!(
+                    read_icc_profile(&buf).as_ref(),
+                    Some(&icc),
+                    "{} -> {}",
+                    source,
+                    target
+                );
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
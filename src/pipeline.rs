@@ -0,0 +1,196 @@
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::filter::AppFilter;
+use crate::process::{modify_part_of_img, BlendMode, BlendOptions, BorderPolicy, PixelBuffer};
+use crate::region::Region;
+
+/// 1つのフィルタと、それを適用する領域と混ぜ方の組。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "FilterProcessRecipe", into = "FilterProcessRecipe")]
+pub struct FilterProcess {
+    pub filter: AppFilter,
+    pub region: Region,
+    pub blend: BlendOptions,
+}
+impl FilterProcess {
+    pub fn new(filter: AppFilter, region: Region, blend: BlendOptions) -> Self {
+        FilterProcess {
+            filter,
+            region,
+            blend,
+        }
+    }
+    /// 領域、混ぜ方、フィルタのオプションが有効か検査する。
+    pub fn validate(&self) -> Result<()> {
+        self.region.validate()?;
+        self.blend
+            .validate()
+            .and_then(|_| self.filter.validate())
+            .map_err(|err| AppError::InvalidOption(err.to_string()))?;
+        Ok(())
+    }
+}
+impl std::fmt::Display for FilterProcess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.filter)?;
+        write!(f, " @ {}", self.region)?;
+        if !self.blend.is_default() {
+            write!(f, " ({})", self.blend)?;
+        }
+        Ok(())
+    }
+}
+
+/// レシピ上のFilterProcessの表現。
+/// 矩形は従来どおり`x`, `y`, `width`, `height`で書き、それ以外の形は`region`に書く。
+/// `feather`、`opacity`、`blend`はデフォルト値なら省略する。
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilterProcessRecipe {
+    filter: AppFilter,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    x: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    y: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    region: Option<Region>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    feather: u32,
+    #[serde(default = "default_opacity", skip_serializing_if = "is_opaque")]
+    opacity: f64,
+    #[serde(default, skip_serializing_if = "is_normal")]
+    blend: BlendMode,
+}
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+fn default_opacity() -> f64 {
+    BlendOptions::default().opacity
+}
+fn is_opaque(value: &f64) -> bool {
+    *value == default_opacity()
+}
+fn is_normal(value: &BlendMode) -> bool {
+    *value == BlendMode::Normal
+}
+impl TryFrom<FilterProcessRecipe> for FilterProcess {
+    type Error = String;
+    fn try_from(value: FilterProcessRecipe) -> Result<Self, Self::Error> {
+        let region = match (value.x, value.y, value.width, value.height, value.region) {
+            (None, None, None, None, Some(region)) => region,
+            (Some(x), Some(y), Some(width), Some(height), None) => Region::Rect {
+                x,
+                y,
+                width,
+                height,
+            },
+            (None, None, None, None, None) => {
+                return Err("either x, y, width and height or region is required.".to_string())
+            }
+            (_, _, _, _, Some(_)) => {
+                return Err("x, y, width and height cannot be used with region.".to_string())
+            }
+            _ => return Err("x, y, width and height must be given together.".to_string()),
+        };
+        let blend = BlendOptions::new(value.feather, value.opacity, value.blend);
+        Ok(FilterProcess::new(value.filter, region, blend))
+    }
+}
+impl From<FilterProcess> for FilterProcessRecipe {
+    fn from(value: FilterProcess) -> Self {
+        let BlendOptions {
+            feather,
+            opacity,
+            mode,
+        } = value.blend;
+        match value.region {
+            Region::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => FilterProcessRecipe {
+                filter: value.filter,
+                x: Some(x),
+                y: Some(y),
+                width: Some(width),
+                height: Some(height),
+                region: None,
+                feather,
+                opacity,
+                blend: mode,
+            },
+            region => FilterProcessRecipe {
+                filter: value.filter,
+                x: None,
+                y: None,
+                width: None,
+                height: None,
+                region: Some(region),
+                feather,
+                opacity,
+                blend: mode,
+            },
+        }
+    }
+}
+
+/// 画像に順に適用するフィルタの列。
+///
+/// ```
+/// use my_img_utilities::filter::{grayscale::GrayscaleFilter, AppFilter};
+/// use my_img_utilities::{BorderPolicy, Pipeline, PixelBuffer, Region};
+///
+/// let img = PixelBuffer::U8(image::RgbaImage::from_pixel(8, 8, image::Rgba([255, 0, 0, 255])));
+/// let region = Region::Rect { x: 0, y: 0, width: 4, height: 8 };
+/// let pipeline = Pipeline::new(BorderPolicy::default())
+///     .then(AppFilter::new(GrayscaleFilter::new()), region);
+/// let out = pipeline.run(img).unwrap();
+/// assert_eq!(out.dimensions(), (8, 8));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    pub processes: Vec<FilterProcess>,
+    /// フィルタが画像の外を読むときの扱い。
+    pub border: BorderPolicy,
+}
+impl Pipeline {
+    pub fn new(border: BorderPolicy) -> Self {
+        Pipeline {
+            processes: Vec::new(),
+            border,
+        }
+    }
+    /// フィルタを領域に普通に重ねる処理を末尾に加える。
+    pub fn then(self, filter: AppFilter, region: Region) -> Self {
+        self.push(FilterProcess::new(filter, region, BlendOptions::default()))
+    }
+    /// 処理を末尾に加える。
+    pub fn push(mut self, process: FilterProcess) -> Self {
+        self.processes.push(process);
+        self
+    }
+    /// すべての処理が有効か検査する。
+    pub fn validate(&self) -> Result<()> {
+        self.processes.iter().try_for_each(FilterProcess::validate)
+    }
+    /// 処理を順に適用する。
+    pub fn run(&self, mut img: PixelBuffer) -> Result<PixelBuffer> {
+        self.validate()?;
+        for FilterProcess {
+            filter,
+            region,
+            blend,
+        } in self.processes.iter()
+        {
+            img = modify_part_of_img(img, region, filter, self.border, blend)?;
+        }
+        Ok(img)
+    }
+}
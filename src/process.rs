@@ -1,9 +1,30 @@
-use anyhow::Result;
-use image::{GenericImage, ImageBuffer, Rgb};
+use anyhow::{anyhow, bail, ensure, Result};
+use clap::ValueEnum;
+use image::{DynamicImage, ImageBuffer, Pixel, Rgba};
+use num_traits::NumCast;
+use rayon::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::ops::Range;
 
-/// FilterProcessorの設定オプションであることを示す。
-pub trait FilterProcessorOptions: std::fmt::Debug + std::fmt::Display + Clone + Default {}
-#[derive(Default, Clone, Debug)]
+use crate::arithmetic::{Subpixel, TripleNums};
+use crate::error::AppError;
+use crate::filter::schema::OptionSpec;
+use crate::region::{Region, RegionMask};
+
+/// FilterProcessorの設定オプションであることを示す。スキーマの検査のためにYAMLの値に変換できる。
+pub trait FilterProcessorOptions:
+    std::fmt::Debug + std::fmt::Display + Clone + Default + serde::Serialize
+{
+    /// スキーマでは表せない、オプションの組み合わせなどを検査する。
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+    /// 設定できるオプションの一覧。値の型と範囲は`validate`の前に検査される。
+    fn schema() -> Vec<OptionSpec> {
+        Vec::new()
+    }
+}
+#[derive(Default, Clone, Debug, Serialize)]
 pub struct EmptyOption;
 impl std::fmt::Display for EmptyOption {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -15,64 +36,804 @@ impl FilterProcessorOptions for EmptyOption {}
 /// 画像処理フィルタであることを示す。
 pub trait FilterProcessor: std::fmt::Debug + std::fmt::Display {
     type OptionsType: FilterProcessorOptions;
-    /// ピクセルバッファを受け取り処理後のバッファを返す。
-    fn process(&self, buf: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> ImageBuffer<Rgb<u8>, Vec<u8>>;
+    /// 処理する矩形を表すビューを受け取り、矩形と同じ大きさの処理後のバッファを返す。
+    /// 矩形の外のピクセルもビューから読める。
+    fn process<S: Subpixel>(&self, src: &SourceView<S>) -> ImageBuffer<Rgba<S>, Vec<S>>
+    where
+        Rgba<S>: Pixel<Subpixel = S>;
     fn get_option(&self) -> Self::OptionsType;
+    /// 出力の1ピクセルを求めるために読む近傍の半径。タイルに分けて処理するときの重なりになる。
+    fn radius(&self) -> u32;
+    /// タイルの大きさをこの値の倍数にそろえる必要があるとき、その値。
+    /// 処理する矩形の左上を基準にした計算をするフィルタが使う。
+    fn tile_alignment(&self) -> u32 {
+        1
+    }
+    /// オプションの値をスキーマで検査し、`FilterProcessorOptions::validate`で組み合わせを検査する。
+    fn validate(&self) -> Result<()> {
+        let option = self.get_option();
+        let values = serde_yaml::to_value(&option)?;
+        for spec in Self::OptionsType::schema() {
+            if let Some(value) = values.get(spec.name) {
+                spec.check(value)?;
+            }
+        }
+        option.validate()
+    }
+}
+
+/// `FilterProcessor`をトレイトオブジェクトとして扱うための版。
+/// サブピクセルの型ごとに処理を分け、オプションはYAMLの値として取り出す。
+/// `Serialize`できるFilterProcessorにはすべて実装される。
+pub trait DynFilterProcessor: std::fmt::Debug + std::fmt::Display + Send + Sync {
+    fn process_u8(&self, src: &SourceView<u8>) -> ImageBuffer<Rgba<u8>, Vec<u8>>;
+    fn process_u16(&self, src: &SourceView<u16>) -> ImageBuffer<Rgba<u16>, Vec<u16>>;
+    fn process_f32(&self, src: &SourceView<f32>) -> ImageBuffer<Rgba<f32>, Vec<f32>>;
+    fn radius(&self) -> u32;
+    fn tile_alignment(&self) -> u32;
+    /// オプションが有効か検査する。
+    fn validate(&self) -> Result<()>;
+    /// オプションをYAMLの値にする。
+    fn options(&self) -> Result<serde_yaml::Value>;
+}
+impl<F> DynFilterProcessor for F
+where
+    F: FilterProcessor + serde::Serialize + Send + Sync,
+{
+    fn process_u8(&self, src: &SourceView<u8>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        self.process(src)
+    }
+    fn process_u16(&self, src: &SourceView<u16>) -> ImageBuffer<Rgba<u16>, Vec<u16>> {
+        self.process(src)
+    }
+    fn process_f32(&self, src: &SourceView<f32>) -> ImageBuffer<Rgba<f32>, Vec<f32>> {
+        self.process(src)
+    }
+    fn radius(&self) -> u32 {
+        FilterProcessor::radius(self)
+    }
+    fn tile_alignment(&self) -> u32 {
+        FilterProcessor::tile_alignment(self)
+    }
+    fn validate(&self) -> Result<()> {
+        FilterProcessor::validate(self)
+    }
+    fn options(&self) -> Result<serde_yaml::Value> {
+        Ok(serde_yaml::to_value(self)?)
+    }
+}
+
+/// 画像の外のピクセルを読むときの扱い。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum BorderPolicy {
+    /// 端のピクセルを引き延ばす。
+    #[default]
+    Clamp,
+    /// 端で鏡のように折り返す。
+    Mirror,
+    /// 反対側の端につなげる。
+    Wrap,
+    /// 指定した色(8bitのRGBA)で埋める。
+    Constant([u8; 4]),
+}
+impl BorderPolicy {
+    /// 長さ`len`の軸上の座標を画像内の座標に直す。画像の外を定数色で埋める場合はNoneを返す。
+    fn resolve(&self, coord: i64, len: u32) -> Option<u32> {
+        let len = len as i64;
+        if (0..len).contains(&coord) {
+            return Some(coord as u32);
+        }
+        let coord = match self {
+            Self::Clamp => coord.clamp(0, len - 1),
+            Self::Mirror => {
+                let coord = coord.rem_euclid(2 * len);
+                if coord < len {
+                    coord
+                } else {
+                    2 * len - 1 - coord
+                }
+            }
+            Self::Wrap => coord.rem_euclid(len),
+            Self::Constant(_) => return None,
+        };
+        Some(coord as u32)
+    }
+}
+impl std::str::FromStr for BorderPolicy {
+    type Err = anyhow::Error;
+    /// `clamp`, `mirror`, `wrap`, `constant[:#rrggbb[aa]]`の形式を受け付ける。
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, color) = match s.split_once(':') {
+            Some((name, color)) => (name, Some(color)),
+            None => (s, None),
+        };
+        match (name.trim(), color) {
+            ("clamp", None) => Ok(Self::Clamp),
+            ("mirror", None) => Ok(Self::Mirror),
+            ("wrap", None) => Ok(Self::Wrap),
+            ("constant", None) => Ok(Self::Constant([0; 4])),
+            ("constant", Some(color)) => parse_hex_color(color.trim()).map(Self::Constant),
+            _ => bail!(
+                "invalid border policy: {} (expected clamp, mirror, wrap or constant[:#rrggbb[aa]])",
+                s
+            ),
+        }
+    }
+}
+impl TryFrom<String> for BorderPolicy {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+impl From<BorderPolicy> for String {
+    fn from(value: BorderPolicy) -> Self {
+        value.to_string()
+    }
+}
+impl std::fmt::Display for BorderPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Clamp => write!(f, "clamp"),
+            Self::Mirror => write!(f, "mirror"),
+            Self::Wrap => write!(f, "wrap"),
+            Self::Constant([r, g, b, a]) => {
+                write!(f, "constant:#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+            }
+        }
+    }
+}
+/// `#rrggbb`または`#rrggbbaa`の色を読む。アルファを省略すると不透明になる。
+fn parse_hex_color(s: &str) -> Result<[u8; 4]> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+        bail!("invalid color: {} (expected #rrggbb or #rrggbbaa)", s);
+    }
+    let mut color = [u8::MAX; 4];
+    for (idx, channel) in color.iter_mut().enumerate().take(hex.len() / 2) {
+        *channel = u8::from_str_radix(&hex[2 * idx..2 * idx + 2], 16)
+            .map_err(|_| anyhow!("invalid color: {} (expected #rrggbb or #rrggbbaa)", s))?;
+    }
+    Ok(color)
+}
+
+/// フィルタの結果 (前景) を元の画像 (背景) に重ねるときの合成モード。
+/// 計算はW3C Compositing and Blending Level 1の定義に従う。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    /// 前景で置き換える。
+    #[default]
+    Normal,
+    /// 乗算。暗くなる。
+    Multiply,
+    /// スクリーン。明るくなる。ぼかしと組み合わせるとグローになる。
+    Screen,
+    /// オーバーレイ。背景の明暗に応じて乗算かスクリーンになる。
+    Overlay,
+    /// ソフトライト。オーバーレイより穏やかにコントラストを変える。
+    #[value(alias = "soft_light")]
+    SoftLight,
+    /// 差の絶対値。
+    Difference,
+    /// 背景の色相と彩度に前景の輝度を使う。グレースケールと組み合わせると彩度が下がる。
+    Luminosity,
+    /// 背景の輝度に前景の色相と彩度を使う。
+    #[value(alias = "colour")]
+    #[serde(alias = "colour")]
+    Color,
+}
+impl BlendMode {
+    /// 背景`base`に前景`top`を合成モードで重ねた色を、前景の代わりとして返す。
+    /// 背景が透明なほど前景の色がそのまま残る。アルファは前景のものを使う。
+    fn composite<S: Subpixel>(&self, base: &Rgba<S>, top: &Rgba<S>) -> Rgba<S>
+    where
+        Rgba<S>: Pixel<Subpixel = S>,
+    {
+        if *self == Self::Normal {
+            return *top;
+        }
+        let max = S::max_f64();
+        let normalize = |color: &Rgba<S>| {
+            let [r, g, b, a] = color.0;
+            let [r, g, b] = (TripleNums([r, g, b]).to_f64() / max).0;
+            let a = a.to_f64().unwrap() / max;
+            ([r, g, b].map(|c| c.clamp(0.0, 1.0)), a.clamp(0.0, 1.0))
+        };
+        let (backdrop, base_alpha) = normalize(base);
+        let (source, _) = normalize(top);
+        let blended = match self {
+            Self::Normal => source,
+            Self::Multiply => separable(backdrop, source, |cb, cs| cb * cs),
+            Self::Screen => separable(backdrop, source, screen),
+            Self::Overlay => separable(backdrop, source, |cb, cs| hard_light(cs, cb)),
+            Self::SoftLight => separable(backdrop, source, soft_light),
+            Self::Difference => separable(backdrop, source, |cb, cs| (cb - cs).abs()),
+            Self::Luminosity => set_lum(backdrop, lum(source)),
+            Self::Color => set_lum(source, lum(backdrop)),
+        };
+        let color = TripleNums(source) * (1.0 - base_alpha) + TripleNums(blended) * base_alpha;
+        let [r, g, b] = (color * max).round::<S>().0;
+        Rgba([r, g, b, top[3]])
+    }
+}
+impl std::fmt::Display for BlendMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.to_possible_value().unwrap();
+        write!(f, "{}", name.get_name())
+    }
+}
+
+fn separable(backdrop: [f64; 3], source: [f64; 3], blend: impl Fn(f64, f64) -> f64) -> [f64; 3] {
+    [0, 1, 2].map(|idx| blend(backdrop[idx], source[idx]))
+}
+fn screen(cb: f64, cs: f64) -> f64 {
+    cb + cs - cb * cs
+}
+fn hard_light(cb: f64, cs: f64) -> f64 {
+    if cs <= 0.5 {
+        cb * 2.0 * cs
+    } else {
+        screen(cb, 2.0 * cs - 1.0)
+    }
+}
+fn soft_light(cb: f64, cs: f64) -> f64 {
+    if cs <= 0.5 {
+        cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb)
+    } else {
+        let d = if cb <= 0.25 {
+            ((16.0 * cb - 12.0) * cb + 4.0) * cb
+        } else {
+            cb.sqrt()
+        };
+        cb + (2.0 * cs - 1.0) * (d - cb)
+    }
+}
+fn lum([r, g, b]: [f64; 3]) -> f64 {
+    0.3 * r + 0.59 * g + 0.11 * b
+}
+/// 色相と彩度を保ったまま輝度を`l`にし、はみ出した成分を範囲内に収める。
+fn set_lum(color: [f64; 3], l: f64) -> [f64; 3] {
+    let d = l - lum(color);
+    let color = color.map(|c| c + d);
+    let l = lum(color);
+    let min = color.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = color.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if min < 0.0 {
+        color.map(|c| l + (c - l) * l / (l - min))
+    } else if max > 1.0 {
+        color.map(|c| l + (c - l) * (1.0 - l) / (max - l))
+    } else {
+        color
+    }
+}
+
+/// フィルタの結果を元の画像に書き戻すときの混ぜ方。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlendOptions {
+    /// 領域の縁から内側へ何ピクセルかけて元の画像に溶け込ませるか。0なら境界はくっきりする。
+    pub feather: u32,
+    /// フィルタの結果を混ぜる割合 (0.0 - 1.0)。
+    pub opacity: f64,
+    /// フィルタの結果を元の画像に重ねるときの合成モード。
+    pub mode: BlendMode,
+}
+impl BlendOptions {
+    pub fn new(feather: u32, opacity: f64, mode: BlendMode) -> Self {
+        BlendOptions {
+            feather,
+            opacity,
+            mode,
+        }
+    }
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            (0.0..=1.0).contains(&self.opacity),
+            "opacity must be between 0 and 1."
+        );
+        Ok(())
+    }
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+impl Default for BlendOptions {
+    fn default() -> Self {
+        BlendOptions {
+            feather: 0,
+            opacity: 1.0,
+            mode: BlendMode::Normal,
+        }
+    }
+}
+impl std::fmt::Display for BlendOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "feather={}px opacity={}% blend={}",
+            self.feather,
+            self.opacity * 100.0,
+            self.mode
+        )
+    }
+}
+
+/// 重み`weight`で`base`に`top`を重ねる。アルファを掛けた色で線形に補間する。
+/// 両方とも透明な場合は色をそのまま補間する。
+fn mix<S: Subpixel>(base: &Rgba<S>, top: &Rgba<S>, weight: f64) -> Rgba<S>
+where
+    Rgba<S>: Pixel<Subpixel = S>,
+{
+    let [br, bg, bb, ba] = base.0;
+    let [tr, tg, tb, ta] = top.0;
+    let base_alpha = ba.to_f64().unwrap() * (1.0 - weight);
+    let top_alpha = ta.to_f64().unwrap() * weight;
+    let alpha = base_alpha + top_alpha;
+    let (base_color, top_color) = (TripleNums([br, bg, bb]), TripleNums([tr, tg, tb]));
+    let [r, g, b] = if alpha > 0.0 {
+        let color = base_color.to_f64() * base_alpha + top_color.to_f64() * top_alpha;
+        (color / alpha).round::<S>().0
+    } else {
+        (base_color.to_f64() * (1.0 - weight) + top_color.to_f64() * weight)
+            .round::<S>()
+            .0
+    };
+    Rgba([r, g, b, S::round_from_f64(alpha)])
 }
 
+/// フィルタに渡す読み取り専用のビュー。処理する矩形の左上を原点とする座標で画像全体を読める。
+/// 画像の外の座標は境界の扱いに従って読む。
+pub struct SourceView<'a, S: Subpixel>
+where
+    Rgba<S>: Pixel<Subpixel = S>,
+{
+    image: &'a ImageBuffer<Rgba<S>, Vec<S>>,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    border: BorderPolicy,
+}
+impl<'a, S: Subpixel> SourceView<'a, S>
+where
+    Rgba<S>: Pixel<Subpixel = S>,
+{
+    pub fn new(
+        image: &'a ImageBuffer<Rgba<S>, Vec<S>>,
+        (x, y, width, height): (u32, u32, u32, u32),
+        border: BorderPolicy,
+    ) -> Self {
+        Self {
+            image,
+            x,
+            y,
+            width,
+            height,
+            border,
+        }
+    }
+    /// 処理する矩形の大きさ。
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+    /// 矩形の左上からの相対座標(x, y)のピクセルを返す。矩形の外も読める。
+    pub fn get_pixel(&self, x: i64, y: i64) -> Rgba<S> {
+        let (img_width, img_height) = self.image.dimensions();
+        let x = self.border.resolve(self.x as i64 + x, img_width);
+        let y = self.border.resolve(self.y as i64 + y, img_height);
+        match (x, y, self.border) {
+            (Some(x), Some(y), _) => *self.image.get_pixel(x, y),
+            (_, _, BorderPolicy::Constant(color)) => Rgba(color.map(|channel| {
+                <S as NumCast>::from(channel as f64 / u8::MAX as f64 * S::max_f64()).unwrap()
+            })),
+            _ => unreachable!("only the constant policy reads outside of the image"),
+        }
+    }
+}
+
+/// サブピクセルの型ごとのRGBAピクセルバッファ。入力画像のビット深度をそのまま保つ。
+#[derive(Debug, Clone)]
+pub enum PixelBuffer {
+    U8(ImageBuffer<Rgba<u8>, Vec<u8>>),
+    U16(ImageBuffer<Rgba<u16>, Vec<u16>>),
+    F32(ImageBuffer<Rgba<f32>, Vec<f32>>),
+}
+impl PixelBuffer {
+    /// デコードした画像を、ビット深度が足りる最小のバッファに変換する。
+    /// RGBA以外の画像は、画像全体をRGBAに展開したバッファを新たに確保する。
+    pub fn from_dynamic(image: DynamicImage) -> Self {
+        match image {
+            DynamicImage::ImageLuma8(_)
+            | DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageRgb8(_)
+            | DynamicImage::ImageRgba8(_) => Self::U8(image.into_rgba8()),
+            DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_) => Self::U16(image.into_rgba16()),
+            _ => Self::F32(image.into_rgba32f()),
+        }
+    }
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Self::U8(buf) => buf.dimensions(),
+            Self::U16(buf) => buf.dimensions(),
+            Self::F32(buf) => buf.dimensions(),
+        }
+    }
+    /// 不透明でないピクセルがあるかどうか。
+    pub fn has_alpha(&self) -> bool {
+        fn any_transparent<S: Subpixel>(buf: &ImageBuffer<Rgba<S>, Vec<S>>) -> bool
+        where
+            Rgba<S>: Pixel<Subpixel = S>,
+        {
+            buf.pixels().any(|pixel| pixel.0[3] < S::DEFAULT_MAX_VALUE)
+        }
+        match self {
+            Self::U8(buf) => any_transparent(buf),
+            Self::U16(buf) => any_transparent(buf),
+            Self::F32(buf) => any_transparent(buf),
+        }
+    }
+    /// 8bitより細かい値を持つかどうか。
+    pub fn is_high_depth(&self) -> bool {
+        !matches!(self, Self::U8(_))
+    }
+}
+impl From<PixelBuffer> for DynamicImage {
+    fn from(value: PixelBuffer) -> Self {
+        match value {
+            PixelBuffer::U8(buf) => Self::ImageRgba8(buf),
+            PixelBuffer::U16(buf) => Self::ImageRgba16(buf),
+            PixelBuffer::F32(buf) => Self::ImageRgba32F(buf),
+        }
+    }
+}
+impl std::fmt::Display for PixelBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (width, height) = self.dimensions();
+        let depth = match self {
+            Self::U8(_) => "8-bit",
+            Self::U16(_) => "16-bit",
+            Self::F32(_) => "32-bit float",
+        };
+        write!(f, "{}x{} ({})", width, height, depth)
+    }
+}
+
+/// `rows`の範囲の行を、各ピクセルを`pixel(x, y)`で求めて埋める。行ごとに並列に計算する。
+/// ピクセルの値は計算する順序によらないので、スレッド数にかかわらず結果は同じになる。
+pub fn par_fill_rows<S, F>(buf: &mut ImageBuffer<Rgba<S>, Vec<S>>, rows: Range<u32>, pixel: F)
+where
+    S: Subpixel,
+    Rgba<S>: Pixel<Subpixel = S>,
+    F: Fn(u32, u32) -> Rgba<S> + Sync,
+{
+    let row_len = buf.width() as usize * 4;
+    if row_len == 0 {
+        return;
+    }
+    let (start, end) = (rows.start as usize * row_len, rows.end as usize * row_len);
+    let samples: &mut [S] = buf;
+    samples[start..end]
+        .par_chunks_mut(row_len)
+        .enumerate()
+        .for_each(|(dy, row)| {
+            let y = rows.start + dy as u32;
+            for (x, out) in row.chunks_exact_mut(4).enumerate() {
+                out.copy_from_slice(&pixel(x as u32, y).0);
+            }
+        });
+}
+
+/// 各ピクセルを`pixel(x, y)`で求めたバッファを作る。行ごとに並列に計算する。
+pub fn par_image_from_fn<S, F>(width: u32, height: u32, pixel: F) -> ImageBuffer<Rgba<S>, Vec<S>>
+where
+    S: Subpixel,
+    Rgba<S>: Pixel<Subpixel = S>,
+    F: Fn(u32, u32) -> Rgba<S> + Sync,
+{
+    let mut buf = ImageBuffer::new(width, height);
+    par_fill_rows(&mut buf, 0..height, pixel);
+    buf
+}
+
+/// 画像全体にフィルタを適用する。
 pub fn modify_whole_img<F>(
-    img: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    img: PixelBuffer,
     processor: &F,
-) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>>
+    border: BorderPolicy,
+    blend: &BlendOptions,
+) -> Result<PixelBuffer>
 where
     F: FilterProcessor,
 {
     let (width, height) = img.dimensions();
-    modify_part_of_img(img, 0, 0, width, height, processor)
+    let region = Region::Rect {
+        x: 0,
+        y: 0,
+        width,
+        height,
+    };
+    modify_part_of_img(img, &region, processor, border, blend)
 }
 
-/// (x, y)の座標をtop-leftとして(width, height)の大きさの矩形を取り扱い、その部分のみにフィルタを適用する。
-/// 適用後の結果をImageBufferとして返す。
+/// `region`の部分のみにフィルタを適用する。フィルタは領域を囲む矩形に対して実行し、
+/// 結果を領域の内側だけ`blend`に従って元の画像に混ぜる。
+/// フィルタは矩形の外のピクセルも参照でき、画像の外は`border`に従って読む。
+/// 適用後の結果をPixelBufferとして返す。フィルタのオプションが不正なら`AppError::InvalidOption`を返す。
+///
+/// 画像はその場で書き換える。タイルに分けて抑えるのはフィルタの作業領域だけで、
+/// 矩形以外の領域やぼかしを使うときのマスクと距離の格子は、領域を囲む矩形の大きさに比例したメモリを使う。
 pub fn modify_part_of_img<F>(
-    mut img: ImageBuffer<Rgb<u8>, Vec<u8>>,
-    x: u32,
-    y: u32,
-    width: u32,
-    height: u32,
+    img: PixelBuffer,
+    region: &Region,
     processor: &F,
-) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>>
+    border: BorderPolicy,
+    blend: &BlendOptions,
+) -> Result<PixelBuffer>
 where
     F: FilterProcessor,
 {
+    processor
+        .validate()
+        .map_err(|err| AppError::InvalidOption(format!("{:#}", err)))?;
     let (img_width, img_height) = img.dimensions();
-    let (x, y, width, height) = if x > img_width || y > img_height {
-        println!("x or y value exceeds the bound of image. process stop.");
-        std::process::exit(2);
-    } else {
-        let width = if x + width > img_width {
-            let width = img_width.saturating_sub(x);
-            println!(
-                "the value of (x + width) exceeds the width of image ({} px). width clamped to {}",
-                img_width, width
-            );
-            width
-        } else {
-            width
+    let mut mask = region.mask(img_width, img_height)?;
+    mask.feather(blend.feather, img_width, img_height);
+    Ok(match img {
+        PixelBuffer::U8(buf) => {
+            PixelBuffer::U8(modify_part_of_buffer(buf, &mask, processor, border, blend))
+        }
+        PixelBuffer::U16(buf) => {
+            PixelBuffer::U16(modify_part_of_buffer(buf, &mask, processor, border, blend))
+        }
+        PixelBuffer::F32(buf) => {
+            PixelBuffer::F32(modify_part_of_buffer(buf, &mask, processor, border, blend))
+        }
+    })
+}
+
+/// タイルの一辺の基準の大きさ。フィルタが一度に扱うピクセル数を抑える。
+const TILE_SIZE: u32 = 512;
+
+/// 処理済みで、まだ画像に書き戻していない1行分のタイル。
+struct PendingRow<S: Subpixel>
+where
+    Rgba<S>: Pixel<Subpixel = S>,
+{
+    /// 領域を囲む矩形の左上からの相対的なy座標。
+    top: u32,
+    height: u32,
+    /// 左から順に並べたタイルの処理結果。
+    tiles: Vec<ImageBuffer<Rgba<S>, Vec<S>>>,
+}
+
+/// 領域を囲む矩形をタイルに分けてフィルタを適用する。
+/// タイルは周囲`radius`ピクセルを元の画像から読むので、処理結果はタイル1行ごとに溜めておき、
+/// 後のタイルがその行を読まなくなった時点で書き戻す。溜める量は半径とタイルの大きさで決まり、画像の大きさによらない。
+/// `BorderPolicy::Wrap`では下端のタイルが画像の上端を読むので、上端から`radius`以内の行は最後に書き戻す。
+fn modify_part_of_buffer<S, F>(
+    mut img: ImageBuffer<Rgba<S>, Vec<S>>,
+    mask: &RegionMask,
+    processor: &F,
+    border: BorderPolicy,
+    blend: &BlendOptions,
+) -> ImageBuffer<Rgba<S>, Vec<S>>
+where
+    S: Subpixel,
+    Rgba<S>: Pixel<Subpixel = S>,
+    F: FilterProcessor,
+{
+    let RegionMask {
+        x,
+        y,
+        width,
+        height,
+        ..
+    } = *mask;
+    let radius = processor.radius();
+    let alignment = processor.tile_alignment().max(1);
+    let tile_size = TILE_SIZE.div_ceil(alignment).saturating_mul(alignment);
+    let mut pending = std::collections::VecDeque::<PendingRow<S>>::new();
+    let mut held = Vec::new();
+    for top in (0..height).step_by(tile_size as usize) {
+        let row_height = tile_size.min(height - top);
+        let tiles = (0..width)
+            .step_by(tile_size as usize)
+            .map(|left| {
+                let tile_width = tile_size.min(width - left);
+                let rect = (x + left, y + top, tile_width, row_height);
+                processor.process(&SourceView::new(&img, rect, border))
+            })
+            .collect();
+        pending.push_back(PendingRow {
+            top,
+            height: row_height,
+            tiles,
+        });
+        // 次のタイル行が読み始める行より上にある結果は書き戻してよい
+        let next_read_top = (top + row_height).saturating_sub(radius);
+        while let Some(row) = pending.front() {
+            if row.top + row.height > next_read_top && top + row_height < height {
+                break;
+            }
+            let row = pending.pop_front().unwrap();
+            if border == BorderPolicy::Wrap && y + row.top < radius {
+                held.push(row);
+            } else {
+                write_back_row(&mut img, mask, blend, row);
+            }
+        }
+    }
+    for row in held {
+        write_back_row(&mut img, mask, blend, row);
+    }
+    img
+}
+
+/// 処理済みのタイル1行を`blend`に従って領域の内側だけ画像に書き戻す。
+fn write_back_row<S>(
+    img: &mut ImageBuffer<Rgba<S>, Vec<S>>,
+    mask: &RegionMask,
+    blend: &BlendOptions,
+    row: PendingRow<S>,
+) where
+    S: Subpixel,
+    Rgba<S>: Pixel<Subpixel = S>,
+{
+    let mut left = 0;
+    for processed in row.tiles {
+        for (i, j, pixel) in processed.enumerate_pixels() {
+            let (i, j) = (left + i, row.top + j);
+            let weight = mask.coverage(i, j) as f64 * blend.opacity;
+            if weight <= 0.0 {
+                continue;
+            }
+            let base = img.get_pixel(mask.x + i, mask.y + j);
+            let top = blend.mode.composite(base, pixel);
+            let result = if weight >= 1.0 {
+                top
+            } else {
+                mix(base, &top, weight)
+            };
+            img.put_pixel(mask.x + i, mask.y + j, result);
+        }
+        left += processed.width();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::registry;
+
+    /// タイルの境界をまたぐ大きさの、透明度の変化する画像。
+    fn image() -> PixelBuffer {
+        PixelBuffer::U8(ImageBuffer::from_fn(1040, 530, |x, y| {
+            Rgba([
+                (x * 7 % 256) as u8,
+                (y * 13 % 256) as u8,
+                ((x ^ y) % 256) as u8,
+                (255 - (x + y) % 64) as u8,
+            ])
+        }))
+    }
+
+    fn samples(img: &PixelBuffer) -> &[u8] {
+        match img {
+            PixelBuffer::U8(buf) => buf.as_raw(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn results_do_not_depend_on_thread_count() {
+        let region = Region::Ellipse {
+            x: 20,
+            y: 10,
+            width: 1000,
+            height: 510,
         };
-        let height = if y + height > img_height {
-            let height = img_height.saturating_sub(y);
-            println!(
-                "the value of (y + height) exceeds the height of image ({} px). height clamped to {}",
-                img_height, height
+        let blend = BlendOptions::new(8, 0.9, BlendMode::Normal);
+        let run = |filter: &crate::filter::AppFilter| {
+            modify_part_of_img(image(), &region, filter, BorderPolicy::Mirror, &blend).unwrap()
+        };
+        let serial = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let parallel = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        for entry in registry::entries() {
+            let filter = entry.build_default().unwrap();
+            let expected = serial.install(|| run(&filter));
+            // デフォルトのプールはCPUの数で決まるので、複数スレッドのプールでも確かめる
+            assert_eq!(samples(&run(&filter)), samples(&expected), "{}", entry.name);
+            assert_eq!(
+                samples(&parallel.install(|| run(&filter))),
+                samples(&expected),
+                "{}",
+                entry.name
             );
-            height
-        } else {
-            height
+        }
+    }
+
+    #[test]
+    fn mosaic_blocks_are_not_split_by_tiles() {
+        use crate::filter::prelude::{MosaicFilter, MosaicFilterOption};
+        // タイルの基準の大きさで割り切れないブロック
+        let size = 300;
+        let filter = MosaicFilter::new(MosaicFilterOption::new(size));
+        let result = modify_whole_img(
+            image(),
+            &filter,
+            BorderPolicy::Clamp,
+            &BlendOptions::default(),
+        )
+        .unwrap();
+        let (PixelBuffer::U8(original), PixelBuffer::U8(result)) = (image(), result) else {
+            unreachable!()
         };
-        (x, y, width, height)
-    };
-    let cropped = img.sub_image(x, y, width, height);
-    let processed = processor.process(&cropped.to_image());
-    img.copy_from(&processed, x, y)?;
-    Ok(img)
+        for (x, y, pixel) in result.enumerate_pixels() {
+            assert_eq!(
+                pixel,
+                original.get_pixel(x - x % size, y - y % size),
+                "({}, {})",
+                x,
+                y
+            );
+        }
+    }
+
+    #[test]
+    fn tiled_results_match_untiled_for_each_border() {
+        // タイルが縦に並ぶ細長い画像
+        let img = ImageBuffer::from_fn(8, 1100, |x, y| {
+            Rgba([
+                (x * 31 % 256) as u8,
+                (y % 256) as u8,
+                (y / 5 % 256) as u8,
+                255,
+            ])
+        });
+        let filter = crate::filter::prelude::GaussianFilter::new(
+            crate::filter::prelude::GaussianFilterOption::new(None, 3.0),
+        );
+        let blend = BlendOptions::default();
+        let borders = [
+            BorderPolicy::Clamp,
+            BorderPolicy::Mirror,
+            BorderPolicy::Wrap,
+            BorderPolicy::Constant([10, 20, 30, 255]),
+        ];
+        for border in borders {
+            let tiled =
+                modify_whole_img(PixelBuffer::U8(img.clone()), &filter, border, &blend).unwrap();
+            let mask = Region::Rect {
+                x: 0,
+                y: 0,
+                width: img.width(),
+                height: img.height(),
+            }
+            .mask(img.width(), img.height())
+            .unwrap();
+            let whole = filter.process(&SourceView::new(
+                &img,
+                (0, 0, img.width(), img.height()),
+                border,
+            ));
+            let mut untiled = img.clone();
+            let row = PendingRow {
+                top: 0,
+                height: img.height(),
+                tiles: vec![whole],
+            };
+            write_back_row(&mut untiled, &mask, &blend, row);
+            assert!(samples(&tiled) == untiled.as_raw().as_slice(), "{}", border);
+        }
+    }
 }
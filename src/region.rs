@@ -0,0 +1,393 @@
+use anyhow::{bail, ensure, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::error::AppError;
+
+/// フィルタを適用する領域。座標は画像の左上を原点とするピクセル単位。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Region {
+    /// (x, y)をtop-leftとする矩形。
+    Rect {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// 矩形に内接する楕円。
+    Ellipse {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// 角を半径`radius`で丸めた矩形。
+    RoundedRect {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        radius: u32,
+    },
+    /// 頂点を順に結んだ多角形。自己交差する場合は偶奇規則で内側を決める。
+    Polygon { points: Vec<[i32; 2]> },
+    /// マスク画像の白いピクセル。マスク画像は元画像の左上に合わせて置く。
+    Mask { path: PathBuf },
+}
+impl Region {
+    /// 大きさや頂点の数が有効か検査する。エラーは`AppError::InvalidRegion`になる。
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Self::Rect { width, height, .. }
+            | Self::Ellipse { width, height, .. }
+            | Self::RoundedRect { width, height, .. } => ensure!(
+                *width > 0 && *height > 0,
+                invalid_region("width and height must be positive.")
+            ),
+            Self::Polygon { points } => {
+                ensure!(
+                    points.len() >= 3,
+                    invalid_region("polygon needs at least 3 points.")
+                )
+            }
+            Self::Mask { path } => {
+                ensure!(
+                    !path.as_os_str().is_empty(),
+                    invalid_region("mask path must not be empty.")
+                )
+            }
+        }
+        Ok(())
+    }
+
+    /// 領域を画像の大きさに合わせて切り取り、ピクセルごとに内側かどうかを求める。
+    /// 画像と重ならない場合はエラーを返す。
+    pub fn mask(&self, img_width: u32, img_height: u32) -> Result<RegionMask> {
+        if let Self::Mask { path } = self {
+            return mask_from_image(path, img_width, img_height);
+        }
+        let bounds = self.bounds();
+        let (left, top, right, bottom) = bounds;
+        let left = left.max(0);
+        let top = top.max(0);
+        let right = right.min(img_width as i64);
+        let bottom = bottom.min(img_height as i64);
+        if left >= right || top >= bottom {
+            bail!(invalid_region(format!(
+                "the region {} does not overlap the image ({}x{} px).",
+                self, img_width, img_height
+            )));
+        }
+        if bounds != (left, top, right, bottom) {
+            log::warn!(
+                "the region {} exceeds the image ({}x{} px). it is clipped.",
+                self,
+                img_width,
+                img_height
+            );
+        }
+        let (x, y) = (left as u32, top as u32);
+        let (width, height) = ((right - left) as u32, (bottom - top) as u32);
+        if let Self::Rect { .. } = self {
+            return Ok(RegionMask {
+                x,
+                y,
+                width,
+                height,
+                coverage: None,
+            });
+        }
+        let mut coverage = Vec::with_capacity((width * height) as usize);
+        for j in 0..height {
+            for i in 0..width {
+                // ピクセルの中心が領域に含まれるかで判定する
+                let px = (x + i) as f64 + 0.5;
+                let py = (y + j) as f64 + 0.5;
+                coverage.push(if self.contains(px, py) { 1.0 } else { 0.0 });
+            }
+        }
+        Ok(RegionMask {
+            x,
+            y,
+            width,
+            height,
+            coverage: Some(coverage),
+        })
+    }
+
+    /// 領域を囲む矩形 (left, top, right, bottom)。rightとbottomは含まない。
+    fn bounds(&self) -> (i64, i64, i64, i64) {
+        match self {
+            Self::Rect {
+                x,
+                y,
+                width,
+                height,
+            }
+            | Self::Ellipse {
+                x,
+                y,
+                width,
+                height,
+            }
+            | Self::RoundedRect {
+                x,
+                y,
+                width,
+                height,
+                ..
+            } => (
+                *x as i64,
+                *y as i64,
+                *x as i64 + *width as i64,
+                *y as i64 + *height as i64,
+            ),
+            Self::Polygon { points } => points.iter().fold(
+                (i64::MAX, i64::MAX, i64::MIN, i64::MIN),
+                |(left, top, right, bottom), &[px, py]| {
+                    (
+                        left.min(px as i64),
+                        top.min(py as i64),
+                        right.max(px as i64 + 1),
+                        bottom.max(py as i64 + 1),
+                    )
+                },
+            ),
+            Self::Mask { .. } => unreachable!("mask regions are bounded by the mask image"),
+        }
+    }
+
+    /// 点(px, py)が領域の内側にあるかどうか。
+    fn contains(&self, px: f64, py: f64) -> bool {
+        match self {
+            Self::Rect { .. } => true,
+            Self::Ellipse {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let rx = *width as f64 / 2.0;
+                let ry = *height as f64 / 2.0;
+                let dx = (px - (*x as f64 + rx)) / rx;
+                let dy = (py - (*y as f64 + ry)) / ry;
+                dx * dx + dy * dy <= 1.0
+            }
+            Self::RoundedRect {
+                x,
+                y,
+                width,
+                height,
+                radius,
+            } => {
+                let radius = (*radius).min(*width / 2).min(*height / 2) as f64;
+                // 角の円の中心から見て外側にある点だけを円で判定する
+                let left = *x as f64 + radius;
+                let right = *x as f64 + *width as f64 - radius;
+                let top = *y as f64 + radius;
+                let bottom = *y as f64 + *height as f64 - radius;
+                let dx = (left - px).max(px - right).max(0.0);
+                let dy = (top - py).max(py - bottom).max(0.0);
+                dx * dx + dy * dy <= radius * radius
+            }
+            Self::Polygon { points } => {
+                let mut inside = false;
+                for (idx, &[x1, y1]) in points.iter().enumerate() {
+                    let [x2, y2] = points[(idx + 1) % points.len()];
+                    let (x1, y1, x2, y2) = (x1 as f64, y1 as f64, x2 as f64, y2 as f64);
+                    if (y1 > py) != (y2 > py) && px < (x2 - x1) * (py - y1) / (y2 - y1) + x1 {
+                        inside = !inside;
+                    }
+                }
+                inside
+            }
+            Self::Mask { .. } => unreachable!("mask regions are read from the mask image"),
+        }
+    }
+}
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => write!(
+                f,
+                "rect (x={} y={} width={} height={})",
+                x, y, width, height
+            ),
+            Self::Ellipse {
+                x,
+                y,
+                width,
+                height,
+            } => write!(
+                f,
+                "ellipse (x={} y={} width={} height={})",
+                x, y, width, height
+            ),
+            Self::RoundedRect {
+                x,
+                y,
+                width,
+                height,
+                radius,
+            } => write!(
+                f,
+                "rounded rect (x={} y={} width={} height={} radius={})",
+                x, y, width, height, radius
+            ),
+            Self::Polygon { points } => {
+                let points = points
+                    .iter()
+                    .map(|[x, y]| format!("({}, {})", x, y))
+                    .collect::<Vec<String>>();
+                write!(f, "polygon [{}]", points.join(", "))
+            }
+            Self::Mask { path } => write!(f, "mask ({})", path.to_string_lossy()),
+        }
+    }
+}
+
+/// 画像に合わせて切り取った領域。(x, y, width, height)の矩形の中で、ピクセルごとに
+/// 領域に含まれる度合いを0.0 (外側) から1.0 (内側) で持つ。
+#[derive(Debug, Clone)]
+pub struct RegionMask {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Noneなら矩形全体が内側。大きな画像で矩形を処理するときにマスクの分のメモリを使わない。
+    coverage: Option<Vec<f32>>,
+}
+impl RegionMask {
+    /// 矩形の左上からの相対座標(x, y)が領域に含まれる度合い。
+    pub fn coverage(&self, x: u32, y: u32) -> f32 {
+        match &self.coverage {
+            Some(coverage) => coverage[y as usize * self.width as usize + x as usize],
+            None => 1.0,
+        }
+    }
+
+    /// 領域の縁から内側`radius`ピクセルにかけて、含まれる度合いを0から1へ線形に上げる。
+    /// 画像の端は縁とみなさないので、画像の端に接する領域はそこではぼけない。
+    /// マスクと距離の格子は、領域を囲む矩形の大きさで確保する。
+    pub fn feather(&mut self, radius: u32, img_width: u32, img_height: u32) {
+        if radius == 0 {
+            return;
+        }
+        // 周囲に1ピクセルずつ足した格子で、領域の外のピクセルまでの距離を求める
+        let (grid_width, grid_height) = (self.width as usize + 2, self.height as usize + 2);
+        let mut dist = vec![f32::INFINITY; grid_width * grid_height];
+        for gy in 0..grid_height {
+            for gx in 0..grid_width {
+                let inner = (1..grid_width - 1).contains(&gx) && (1..grid_height - 1).contains(&gy);
+                let outside = if inner {
+                    self.coverage((gx - 1) as u32, (gy - 1) as u32) <= 0.0
+                } else {
+                    let x = self.x as i64 + gx as i64 - 1;
+                    let y = self.y as i64 + gy as i64 - 1;
+                    (0..img_width as i64).contains(&x) && (0..img_height as i64).contains(&y)
+                };
+                if outside {
+                    dist[gy * grid_width + gx] = 0.0;
+                }
+            }
+        }
+        // 2パスのchamfer距離変換。斜めの隣は√2とする
+        let diagonal = std::f32::consts::SQRT_2;
+        let forward = [
+            (-1, -1, diagonal),
+            (0, -1, 1.0),
+            (1, -1, diagonal),
+            (-1, 0, 1.0),
+        ];
+        let backward = [
+            (1, 1, diagonal),
+            (0, 1, 1.0),
+            (-1, 1, diagonal),
+            (1, 0, 1.0),
+        ];
+        let mut relax = |gx: usize, gy: usize, neighbors: &[(i64, i64, f32)]| {
+            for &(dx, dy, cost) in neighbors {
+                let (nx, ny) = (gx as i64 + dx, gy as i64 + dy);
+                if (0..grid_width as i64).contains(&nx) && (0..grid_height as i64).contains(&ny) {
+                    let d = dist[ny as usize * grid_width + nx as usize] + cost;
+                    let current = &mut dist[gy * grid_width + gx];
+                    *current = current.min(d);
+                }
+            }
+        };
+        for gy in 0..grid_height {
+            for gx in 0..grid_width {
+                relax(gx, gy, &forward);
+            }
+        }
+        for gy in (0..grid_height).rev() {
+            for gx in (0..grid_width).rev() {
+                relax(gx, gy, &backward);
+            }
+        }
+        let ramp = (radius + 1) as f32;
+        let len = self.width as usize * self.height as usize;
+        let coverage = self.coverage.get_or_insert_with(|| vec![1.0; len]);
+        for y in 0..self.height as usize {
+            for x in 0..self.width as usize {
+                let value = &mut coverage[y * self.width as usize + x];
+                let d = dist[(y + 1) * grid_width + x + 1];
+                *value *= (d / ramp).min(1.0);
+            }
+        }
+    }
+}
+
+fn invalid_region<M: Into<String>>(message: M) -> AppError {
+    AppError::InvalidRegion(message.into())
+}
+
+/// マスク画像を読み、明るさが半分以上のピクセルを内側とする。
+/// 白いピクセルを囲む最小の矩形に切り詰める。
+fn mask_from_image(path: &Path, img_width: u32, img_height: u32) -> Result<RegionMask> {
+    let mask = image::open(path)
+        .with_context(|| {
+            invalid_region(format!(
+                "failed to open mask image: {}",
+                path.to_string_lossy()
+            ))
+        })?
+        .into_luma8();
+    let width = mask.width().min(img_width);
+    let height = mask.height().min(img_height);
+    let is_white = |x: u32, y: u32| mask.get_pixel(x, y).0[0] >= 128;
+    let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+    for y in 0..height {
+        for x in 0..width {
+            if is_white(x, y) {
+                left = left.min(x);
+                top = top.min(y);
+                right = right.max(x + 1);
+                bottom = bottom.max(y + 1);
+            }
+        }
+    }
+    if left >= right || top >= bottom {
+        bail!(invalid_region(format!(
+            "the mask image has no white pixels inside the image: {}",
+            path.to_string_lossy()
+        )));
+    }
+    let coverage = (top..bottom)
+        .flat_map(|y| (left..right).map(move |x| (x, y)))
+        .map(|(x, y)| if is_white(x, y) { 1.0 } else { 0.0 })
+        .collect();
+    Ok(RegionMask {
+        x: left,
+        y: top,
+        width: right - left,
+        height: bottom - top,
+        coverage: Some(coverage),
+    })
+}